@@ -0,0 +1,139 @@
+// False color maps, applied after the stretch. Each maps a displayed brightness to a color.
+
+use std::f64::consts::PI;
+
+// entries in a lookup table, far more than can be told apart on screen
+const TABLE_STEPS: usize = 4096;
+
+// polynomial fit of matplotlib's viridis, https://www.shadertoy.com/view/WlfXRN
+const VIRIDIS: [[f64; 3]; 7] = [
+    [
+        0.277_727_327_223_417_7,
+        0.005_407_344_544_966_578,
+        0.334_099_805_335_306_1,
+    ],
+    [
+        0.105_093_043_108_577_4,
+        1.404_613_529_898_575,
+        1.384_590_162_594_685,
+    ],
+    [
+        -0.330_861_828_725_556_3,
+        0.214_847_559_468_213,
+        0.095_095_163_028_236_59,
+    ],
+    [
+        -4.634_230_498_983_486,
+        -5.799_100_973_351_585,
+        -19.332_440_956_279_87,
+    ],
+    [
+        6.228_269_936_347_081,
+        14.179_933_366_805_09,
+        56.690_552_600_681_05,
+    ],
+    [
+        4.776_384_997_670_288,
+        -13.745_145_377_746_01,
+        -65.353_032_633_372_34,
+    ],
+    [
+        -5.435_455_855_934_631,
+        4.645_852_612_178_535,
+        26.312_435_249_583_2,
+    ],
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Colormap {
+    Grey,
+    Viridis,
+    Heat,
+    Cubehelix,
+    // grey, with pixels clipped to black shown blue and pixels clipped to white shown red
+    Clip,
+}
+
+fn viridis(x: f64) -> [f64; 3] {
+    let mut result = [0.0; 3];
+    for (channel, value) in result.iter_mut().enumerate() {
+        // horner's method, highest power first
+        *value = VIRIDIS
+            .iter()
+            .rev()
+            .fold(0.0, |acc, coefficients| acc * x + coefficients[channel]);
+    }
+    result
+}
+
+// black, red, yellow, white
+fn heat(x: f64) -> [f64; 3] {
+    [x * 3.0, x * 3.0 - 1.0, x * 3.0 - 2.0]
+}
+
+// https://people.phy.cam.ac.uk/dag9/CUBEHELIX/, with the default start 0.5, -1.5 rotations,
+// hue 1 and gamma 1
+fn cubehelix(x: f64) -> [f64; 3] {
+    let angle = 2.0 * PI * (0.5 / 3.0 + 1.0 - 1.5 * x);
+    let amplitude = x * (1.0 - x) / 2.0;
+    let (sin, cos) = angle.sin_cos();
+    [
+        x + amplitude * (-0.148_61 * cos + 1.782_77 * sin),
+        x + amplitude * (-0.292_27 * cos - 0.906_49 * sin),
+        x + amplitude * (1.972_94 * cos),
+    ]
+}
+
+impl Colormap {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "grey" | "gray" => Some(Colormap::Grey),
+            "viridis" => Some(Colormap::Viridis),
+            "heat" => Some(Colormap::Heat),
+            "cubehelix" => Some(Colormap::Cubehelix),
+            "clip" => Some(Colormap::Clip),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Colormap::Grey => "grey",
+            Colormap::Viridis => "viridis",
+            Colormap::Heat => "heat",
+            Colormap::Cubehelix => "cubehelix",
+            Colormap::Clip => "clip",
+        }
+    }
+
+    // x is the stretched value, which is only clamped to 0 to 1 here, so clipping can be seen
+    pub fn color(self, x: f64) -> [u8; 4] {
+        let clamped = x.max(0.0).min(1.0);
+        let rgb = match self {
+            Colormap::Grey => [clamped; 3],
+            Colormap::Viridis => viridis(clamped),
+            Colormap::Heat => heat(clamped),
+            Colormap::Cubehelix => cubehelix(clamped),
+            Colormap::Clip if x <= 0.0 => [0.0, 0.0, 1.0],
+            Colormap::Clip if x >= 1.0 => [1.0, 0.0, 0.0],
+            Colormap::Clip => [clamped; 3],
+        };
+        let to_u8 = |value: f64| (value.max(0.0).min(1.0) * 255.0).round() as u8;
+        [to_u8(rgb[0]), to_u8(rgb[1]), to_u8(rgb[2]), 255]
+    }
+
+    /// Colors for stretched values from 0 to 1 in even steps. It doesn't depend on the stretch,
+    /// so it only needs building again when the colormap changes.
+    pub fn lookup_table(self) -> Vec<[u8; 4]> {
+        let last = (TABLE_STEPS - 1) as f64;
+        (0..TABLE_STEPS)
+            .map(|step| self.color(step as f64 / last))
+            .collect()
+    }
+
+    /// Index into lookup_table. Values outside 0 to 1 go to the ends, which are the clip colors.
+    pub fn table_index(x: f32) -> usize {
+        let last = (TABLE_STEPS - 1) as f32;
+        (x.max(0.0).min(1.0) * last).round() as usize
+    }
+}
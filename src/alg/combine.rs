@@ -0,0 +1,145 @@
+use super::{mean, median};
+use crate::{camera::interface::ROIImage, Result};
+use khygl::texture::CpuTexture;
+use std::sync::Arc;
+
+// values further than this many standard deviations from the median are rejected
+const KAPPA: f64 = 3.0;
+const MAX_ITERATIONS: usize = 5;
+// standard deviation of normally distributed values per unit of median absolute deviation
+const MAD_TO_SIGMA: f64 = 1.4826;
+// frames are integer counts, so identical values otherwise make any one count difference an outlier
+const MIN_SIGMA: f64 = 1.0;
+
+/// How outliers (cosmic rays, satellites, hot pixel flicker) are handled when combining.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rejection {
+    // drop outliers
+    SigmaClip,
+    // pull outliers in to the rejection limits, which keeps more signal with few frames
+    Winsorize,
+}
+
+impl Rejection {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "sigma" => Some(Rejection::SigmaClip),
+            "winsor" => Some(Rejection::Winsorize),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Rejection::SigmaClip => "sigma",
+            Rejection::Winsorize => "winsor",
+        }
+    }
+}
+
+// the median and MAD are used as the centre and spread, as the mean and standard deviation are
+// dragged towards the outliers they're meant to find, too far to reject any with few frames
+#[allow(clippy::float_cmp)]
+fn rejected_mean(values: &mut Vec<f64>, deviations: &mut Vec<f64>, rejection: Rejection) -> f64 {
+    for _ in 0..MAX_ITERATIONS {
+        if values.len() < 3 {
+            break;
+        }
+        let center = median(values);
+        deviations.clear();
+        deviations.extend(values.iter().map(|value| (value - center).abs()));
+        let sigma = (median(deviations) * MAD_TO_SIGMA).max(MIN_SIGMA);
+        let (low, high) = (center - KAPPA * sigma, center + KAPPA * sigma);
+        let changed = match rejection {
+            Rejection::SigmaClip => {
+                let before = values.len();
+                values.retain(|&value| value >= low && value <= high);
+                values.len() != before
+            }
+            Rejection::Winsorize => {
+                let mut changed = false;
+                for value in values.iter_mut() {
+                    let clamped = value.max(low).min(high);
+                    changed |= clamped != *value;
+                    *value = clamped;
+                }
+                changed
+            }
+        };
+        if !changed {
+            break;
+        }
+    }
+    mean(values.iter().cloned())
+}
+
+/// Per pixel mean of the frames, with outliers rejected. The metadata is taken from the first
+/// frame, except for the temperature, which is averaged.
+pub fn combine(frames: &[Arc<ROIImage>], rejection: Rejection) -> Result<ROIImage> {
+    let first = frames.first().ok_or("No frames to combine")?;
+    let same_roi = |frame: &ROIImage| {
+        let (a, b) = (&frame.location, &first.location);
+        (a.x, a.y, a.width, a.height) == (b.x, b.y, b.width, b.height)
+    };
+    if frames
+        .iter()
+        .any(|frame| !same_roi(frame) || frame.image.size != first.image.size)
+    {
+        return Err("Frames to combine must all have the same size and ROI".into());
+    }
+    let size = first.image.size;
+    let mut data = Vec::with_capacity(size.0 * size.1);
+    let mut values = Vec::with_capacity(frames.len());
+    let mut deviations = Vec::with_capacity(frames.len());
+    for index in 0..size.0 * size.1 {
+        values.clear();
+        values.extend(
+            frames
+                .iter()
+                .map(|frame| f64::from(frame.image.data()[index])),
+        );
+        let mean = rejected_mean(&mut values, &mut deviations, rejection);
+        data.push(mean.round().max(0.0).min(f64::from(u16::max_value())) as u16);
+    }
+    let mut meta = first.meta.clone();
+    let temperatures = frames
+        .iter()
+        .filter_map(|frame| frame.meta.temperature)
+        .collect::<Vec<_>>();
+    if !temperatures.is_empty() {
+        meta.temperature = Some(temperatures.iter().sum::<f64>() / temperatures.len() as f64);
+    }
+    Ok(ROIImage {
+        image: CpuTexture::new(data, size),
+        location: first.location.clone(),
+        original: first.original.clone(),
+        meta,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_single_outlier() {
+        for &rejection in &[Rejection::SigmaClip, Rejection::Winsorize] {
+            let mut values = vec![1000.0, 1004.0, 998.0, 1002.0, 30000.0];
+            let mean = rejected_mean(&mut values, &mut Vec::new(), rejection);
+            assert!(
+                (mean - 1001.0).abs() < 5.0,
+                "{}: {}",
+                rejection.name(),
+                mean
+            );
+        }
+    }
+
+    #[test]
+    fn keeps_identical_frames() {
+        let mut values = vec![500.0; 5];
+        let mean = rejected_mean(&mut values, &mut Vec::new(), Rejection::SigmaClip);
+        assert!((mean - 500.0).abs() < 1e-9);
+        assert_eq!(values.len(), 5);
+    }
+}
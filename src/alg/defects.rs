@@ -0,0 +1,171 @@
+use super::{histogram::Histogram, median};
+use crate::{camera::interface::ROIImage, Result};
+use khygl::texture::CpuTexture;
+use std::{collections::HashSet, sync::Arc};
+
+// deviation from the expected value, in units of noise, that marks a pixel as defective
+const DEFECT_SIGMA: f64 = 8.0;
+// neighbors of an isolated defect stay within this many noise units of the background. A star
+// brightens its neighbors too, so it isn't mistaken for a hot pixel.
+const NEIGHBOR_SIGMA: f64 = 3.0;
+const MAD_TO_SIGMA: f64 = 1.4826;
+
+/// Hot and cold pixels of one camera, in sensor coordinates at one binning.
+#[derive(Debug)]
+pub struct DefectMap {
+    pub camera: String,
+    pub bin: usize,
+    pixels: HashSet<(usize, usize)>,
+}
+
+// background and noise from median and MAD, which defects barely affect
+fn background_noise(image: &Arc<ROIImage>) -> (f64, f64) {
+    let histogram = Histogram::compute(image);
+    let median = histogram.median();
+    let noise = histogram.median_absolute_deviation(median) * MAD_TO_SIGMA;
+    (f64::from(median), noise.max(1.0))
+}
+
+fn neighbors((x, y): (usize, usize), size: (usize, usize)) -> impl Iterator<Item = (usize, usize)> {
+    (-1isize..=1)
+        .flat_map(|dy| (-1isize..=1).map(move |dx| (dx, dy)))
+        .filter(|&offset| offset != (0, 0))
+        .filter_map(move |(dx, dy)| {
+            let (nx, ny) = (x as isize + dx, y as isize + dy);
+            if nx < 0 || ny < 0 || nx as usize >= size.0 || ny as usize >= size.1 {
+                None
+            } else {
+                Some((nx as usize, ny as usize))
+            }
+        })
+}
+
+impl DefectMap {
+    /// Every pixel far from the median of a master dark. Darks have no stars, so no
+    /// neighborhood test is needed.
+    pub fn from_dark(dark: &Arc<ROIImage>) -> Self {
+        let (background, noise) = background_noise(dark);
+        let mut pixels = HashSet::new();
+        for (index, &value) in dark.image.data().iter().enumerate() {
+            if (f64::from(value) - background).abs() > noise * DEFECT_SIGMA {
+                let (x, y) = (index % dark.image.size.0, index / dark.image.size.0);
+                pixels.insert((x + dark.location.x, y + dark.location.y));
+            }
+        }
+        Self {
+            camera: dark.meta.camera.clone(),
+            bin: dark.meta.bin,
+            pixels,
+        }
+    }
+
+    /// Pixels far from the median of their neighbors, while the neighbors themselves look
+    /// like background.
+    pub fn from_frame(frame: &Arc<ROIImage>) -> Self {
+        let (background, noise) = background_noise(frame);
+        let image = &frame.image;
+        let mut pixels = HashSet::new();
+        let mut values = Vec::with_capacity(8);
+        for y in 0..image.size.1 {
+            for x in 0..image.size.0 {
+                values.clear();
+                values.extend(neighbors((x, y), image.size).map(|pixel| f64::from(image[pixel])));
+                let around = median(&mut values);
+                let deviation = (f64::from(image[(x, y)]) - around).abs();
+                if deviation > noise * DEFECT_SIGMA
+                    && (around - background).abs() < noise * NEIGHBOR_SIGMA
+                {
+                    pixels.insert((x + frame.location.x, y + frame.location.y));
+                }
+            }
+        }
+        Self {
+            camera: frame.meta.camera.clone(),
+            bin: frame.meta.bin,
+            pixels,
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.pixels.len()
+    }
+
+    /// Replaces each defect with the median of its good neighbors.
+    pub fn apply(&self, image: &ROIImage) -> ROIImage {
+        let location = &image.location;
+        let mut corrected = image.image.data().to_vec();
+        let size = image.image.size;
+        let is_defect =
+            |(x, y): (usize, usize)| self.pixels.contains(&(x + location.x, y + location.y));
+        let mut values = Vec::with_capacity(8);
+        for &(x, y) in &self.pixels {
+            if x < location.x || y < location.y {
+                continue;
+            }
+            let pixel = (x - location.x, y - location.y);
+            if pixel.0 >= size.0 || pixel.1 >= size.1 {
+                continue;
+            }
+            values.clear();
+            values.extend(
+                neighbors(pixel, size)
+                    .filter(|&neighbor| !is_defect(neighbor))
+                    .map(|neighbor| f64::from(image.image[neighbor])),
+            );
+            // a cluster of defects with no good neighbors is left alone
+            if !values.is_empty() {
+                corrected[pixel.1 * size.0 + pixel.0] = median(&mut values).round() as u16;
+            }
+        }
+        ROIImage {
+            image: CpuTexture::new(corrected, size),
+            location: image.location.clone(),
+            original: image.original.clone(),
+            meta: image.meta.clone(),
+        }
+    }
+
+    pub fn parse(contents: &str) -> Result<Self> {
+        let mut lines = contents.lines();
+        let camera = lines
+            .next()
+            .and_then(|line| line.strip_prefix("camera "))
+            .ok_or("Defect map must start with a camera line")?
+            .to_string();
+        let bin = lines
+            .next()
+            .and_then(|line| {
+                let mut parts = line.split_whitespace();
+                match (parts.next(), parts.next()) {
+                    (Some("bin"), Some(bin)) => bin.parse().ok(),
+                    _ => None,
+                }
+            })
+            .ok_or("Defect map must have a bin line after the camera")?;
+        let mut pixels = HashSet::new();
+        for line in lines {
+            let mut parts = line.split_whitespace();
+            match (parts.next(), parts.next()) {
+                (Some(x), Some(y)) => {
+                    pixels.insert((x.parse()?, y.parse()?));
+                }
+                _ => return Err(format!("Invalid defect map line: {}", line).into()),
+            }
+        }
+        Ok(Self {
+            camera,
+            bin,
+            pixels,
+        })
+    }
+
+    pub fn format(&self) -> String {
+        let mut pixels = self.pixels.iter().collect::<Vec<_>>();
+        pixels.sort();
+        let mut contents = format!("camera {}\nbin {}\n", self.camera, self.bin);
+        for (x, y) in pixels {
+            contents.push_str(&format!("{} {}\n", x, y));
+        }
+        contents
+    }
+}
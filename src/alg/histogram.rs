@@ -0,0 +1,154 @@
+use crate::camera::interface::ROIImage;
+use std::{sync::Arc, thread::spawn};
+
+// one bin per u16 value, so percentiles are exact
+pub const BINS: usize = 1 << 16;
+// bins shown on screen, each covering 256 adjacent u16 values
+pub const DISPLAY_BINS: usize = 256;
+// counting is split across this many threads for large frames
+const THREADS: usize = 4;
+const MIN_PIXELS_PER_THREAD: usize = 1 << 18;
+
+#[derive(Debug)]
+pub struct Histogram {
+    counts: Vec<u64>,
+    total: u64,
+}
+
+fn count(data: &[u16]) -> Vec<u64> {
+    let mut counts = vec![0; BINS];
+    for &value in data {
+        counts[usize::from(value)] += 1;
+    }
+    counts
+}
+
+impl Histogram {
+    pub fn compute(image: &Arc<ROIImage>) -> Self {
+        let len = image.image.data().len();
+        let threads = (len / MIN_PIXELS_PER_THREAD).max(1).min(THREADS);
+        let chunk = (len + threads - 1) / threads;
+        // the calling thread takes the first chunk itself
+        let handles = (1..threads)
+            .map(|index| {
+                let image = image.clone();
+                spawn(move || {
+                    let data = image.image.data();
+                    count(&data[(index * chunk).min(len)..((index + 1) * chunk).min(len)])
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut counts = count(&image.image.data()[..chunk.min(len)]);
+        for handle in handles {
+            let partial = handle.join().expect("histogram thread panicked");
+            for (total, partial) in counts.iter_mut().zip(partial) {
+                *total += partial;
+            }
+        }
+        Self {
+            counts,
+            total: len as u64,
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    pub fn mean(&self) -> f64 {
+        let sum: f64 = self
+            .counts
+            .iter()
+            .enumerate()
+            .map(|(value, &count)| value as f64 * count as f64)
+            .sum();
+        sum / self.total as f64
+    }
+
+    pub fn stdev(&self, mean: f64) -> f64 {
+        let sum: f64 = self
+            .counts
+            .iter()
+            .enumerate()
+            .map(|(value, &count)| {
+                let diff = mean - value as f64;
+                diff * diff * count as f64
+            })
+            .sum();
+        (sum / self.total as f64).sqrt()
+    }
+
+    // the value that would be at this index if the pixels were sorted
+    pub fn value_at(&self, index: u64) -> u16 {
+        let mut seen = 0;
+        for (value, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen > index {
+                return value as u16;
+            }
+        }
+        u16::max_value()
+    }
+
+    pub fn median(&self) -> u16 {
+        self.value_at(self.total / 2)
+    }
+
+    // median absolute deviation around the given median, a noise estimate that ignores stars
+    pub fn median_absolute_deviation(&self, median: u16) -> f64 {
+        let median = usize::from(median);
+        let mut seen = self.counts[median];
+        let mut deviation = 0;
+        while seen <= self.total / 2 && deviation < BINS {
+            deviation += 1;
+            if deviation <= median {
+                seen += self.counts[median - deviation];
+            }
+            if median + deviation < BINS {
+                seen += self.counts[median + deviation];
+            }
+        }
+        deviation as f64
+    }
+
+    // lookup table that spreads the pixels evenly over the whole output range
+    pub fn equalization(&self) -> Vec<u16> {
+        let lowest = self.counts.iter().cloned().find(|&count| count > 0);
+        let (lowest, range) = match lowest {
+            Some(lowest) if self.total > lowest => (lowest, (self.total - lowest) as f64),
+            // a single value everywhere, nothing to spread
+            _ => return vec![0; BINS],
+        };
+        let max = f64::from(u16::max_value());
+        let mut seen = 0;
+        self.counts
+            .iter()
+            .map(|&count| {
+                seen += count;
+                (seen.saturating_sub(lowest) as f64 / range * max).round() as u16
+            })
+            .collect()
+    }
+
+    // bar heights from 0 to 1, either linear in count or in log(count + 1)
+    pub fn heights(&self, log: bool) -> Vec<f64> {
+        let group = BINS / DISPLAY_BINS;
+        let scale = |count: u64| {
+            if log {
+                (count as f64 + 1.0).ln()
+            } else {
+                count as f64
+            }
+        };
+        let bars = self
+            .counts
+            .chunks(group)
+            .map(|chunk| scale(chunk.iter().sum()))
+            .collect::<Vec<_>>();
+        let max = bars.iter().cloned().fold(0.0, f64::max);
+        if max <= 0.0 {
+            return vec![0.0; bars.len()];
+        }
+        bars.iter().map(|&bar| bar / max).collect()
+    }
+}
@@ -0,0 +1,244 @@
+use super::{
+    median,
+    register::{register, Transform, MATCH_STARS, MIN_MATCHES},
+    sample_bilinear,
+    starfinder::find_stars,
+    StackedImage,
+};
+use crate::{
+    camera::{interface::ROIImage, CameraId},
+    Result, SendUserUpdate, UserUpdate,
+};
+use std::{
+    fmt::Write,
+    sync::{mpsc, Arc},
+    thread::spawn,
+    time::{Duration, Instant},
+};
+
+// frames whose median HFR is this much worse than the reference's are rejected
+const MAX_HFR_RATIO: f64 = 1.5;
+
+enum StackCommand {
+    Frame(Arc<ROIImage>),
+    Reset,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct StackStatus {
+    pub stacked: u64,
+    pub rejected: u64,
+    pub last_rejection: Option<String>,
+    pub reference_hfr: Option<f64>,
+    pub last_transform: Option<Transform>,
+    pub last_matches: usize,
+    pub duration: Duration,
+}
+
+#[derive(Debug)]
+pub struct StackResult {
+    // None when the frame was rejected, and the stack didn't change
+    pub stack: Option<StackedImage>,
+    pub status: StackStatus,
+}
+
+// star positions brightest first, and the median HFR of the unsaturated ones
+fn measure_stars(image: &Arc<ROIImage>) -> (Vec<(f64, f64)>, Option<f64>) {
+    let mut stars = find_stars(&image.image);
+    stars.retain(|star| !star.saturated);
+    stars.sort_by(|l, r| r.flux.total_cmp(&l.flux));
+    let mut hfr = stars.iter().map(|star| star.hfr).collect::<Vec<_>>();
+    let hfr = if hfr.is_empty() {
+        None
+    } else {
+        Some(median(&mut hfr))
+    };
+    let positions = stars
+        .iter()
+        .take(MATCH_STARS)
+        .map(|star| (star.x, star.y))
+        .collect();
+    (positions, hfr)
+}
+
+struct Stack {
+    reference: Option<Arc<ROIImage>>,
+    reference_stars: Vec<(f64, f64)>,
+    sum: Vec<f64>,
+    // frames that covered each pixel, which differs near the edges once frames drift
+    count: Vec<u32>,
+    status: StackStatus,
+}
+
+impl Stack {
+    fn new() -> Self {
+        Self {
+            reference: None,
+            reference_stars: Vec::new(),
+            sum: Vec::new(),
+            count: Vec::new(),
+            status: StackStatus::default(),
+        }
+    }
+
+    fn add(&mut self, frame: &Arc<ROIImage>) -> Result<()> {
+        let (stars, hfr) = measure_stars(frame);
+        // the first frame becomes the reference, once it has passed the same checks as the rest
+        let (reference_stars, reference_hfr) = match self.reference {
+            Some(ref reference) => {
+                if frame.image.size != reference.image.size {
+                    return Err("Frame size differs from the reference, reset the stack".into());
+                }
+                (self.reference_stars.clone(), self.status.reference_hfr)
+            }
+            None => {
+                if stars.len() < MIN_MATCHES {
+                    return Err(format!("Only {} stars in reference frame", stars.len()).into());
+                }
+                (stars.clone(), hfr)
+            }
+        };
+        if let (Some(hfr), Some(reference_hfr)) = (hfr, reference_hfr) {
+            if hfr > reference_hfr * MAX_HFR_RATIO {
+                return Err(format!(
+                    "HFR {:.2} is worse than reference {:.2}",
+                    hfr, reference_hfr
+                )
+                .into());
+            }
+        }
+        let (transform, matches) = register(&stars, &reference_stars)
+            .ok_or_else(|| format!("Unable to match {} stars to the reference", stars.len()))?;
+        if self.reference.is_none() {
+            let len = frame.image.size.0 * frame.image.size.1;
+            self.sum = vec![0.0; len];
+            self.count = vec![0; len];
+            self.reference = Some(frame.clone());
+            self.reference_stars = reference_stars;
+            self.status.reference_hfr = reference_hfr;
+        }
+        // each stack pixel is sampled from where it lands in the frame
+        let inverse = transform.inverse();
+        let width = frame.image.size.0;
+        for (index, (sum, count)) in self.sum.iter_mut().zip(self.count.iter_mut()).enumerate() {
+            let (x, y) = inverse.apply(((index % width) as f64, (index / width) as f64));
+            if let Some(value) = sample_bilinear(&frame.image, x, y) {
+                *sum += value;
+                *count += 1;
+            }
+        }
+        self.status.last_transform = Some(transform);
+        self.status.last_matches = matches;
+        Ok(())
+    }
+
+    fn image(&self) -> Option<StackedImage> {
+        let reference = self.reference.as_ref()?;
+        Some(StackedImage::average(&self.sum, &self.count, reference))
+    }
+}
+
+/// Registers and averages frames on its own thread. Frames arriving while the previous one
+/// is still being stacked are dropped.
+pub struct LiveStack {
+    send: mpsc::SyncSender<StackCommand>,
+    status: StackStatus,
+    dropped: u64,
+}
+
+impl LiveStack {
+    pub fn new(send_user_update: SendUserUpdate, id: CameraId) -> Self {
+        let (send, recv) = mpsc::sync_channel(1);
+        spawn(move || {
+            let mut stack = Stack::new();
+            while let Ok(command) = recv.recv() {
+                let frame = match command {
+                    StackCommand::Frame(frame) => frame,
+                    StackCommand::Reset => {
+                        stack = Stack::new();
+                        continue;
+                    }
+                };
+                let begin = Instant::now();
+                let stacked = match stack.add(&frame) {
+                    Ok(()) => {
+                        stack.status.stacked += 1;
+                        stack.status.last_rejection = None;
+                        stack.image()
+                    }
+                    Err(err) => {
+                        stack.status.rejected += 1;
+                        stack.status.last_rejection = Some(err.to_string());
+                        None
+                    }
+                };
+                stack.status.duration = Instant::now() - begin;
+                let result = StackResult {
+                    stack: stacked,
+                    status: stack.status.clone(),
+                };
+                if send_user_update
+                    .send_event(UserUpdate::StackResult(id, result))
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        });
+        Self {
+            send,
+            status: StackStatus::default(),
+            dropped: 0,
+        }
+    }
+
+    pub fn add(&mut self, frame: Arc<ROIImage>) -> Result<()> {
+        match self.send.try_send(StackCommand::Frame(frame)) {
+            Ok(()) => Ok(()),
+            Err(mpsc::TrySendError::Full(_)) => {
+                self.dropped += 1;
+                Ok(())
+            }
+            Err(mpsc::TrySendError::Disconnected(_)) => Err("Stacking thread disconnected".into()),
+        }
+    }
+
+    pub fn reset(&mut self) -> Result<()> {
+        // a reset must not be dropped, so wait for the queue
+        self.send
+            .send(StackCommand::Reset)
+            .map_err(|_| "Stacking thread disconnected")?;
+        self.status = StackStatus::default();
+        self.dropped = 0;
+        Ok(())
+    }
+
+    pub fn user_update(&mut self, status: StackStatus) {
+        self.status = status;
+    }
+
+    pub fn status(&self, status: &mut String) -> Result<()> {
+        writeln!(
+            status,
+            "livestack: {} stacked, {} rejected, {} dropped ({:?})",
+            self.status.stacked, self.status.rejected, self.dropped, self.status.duration
+        )?;
+        if let Some(reference_hfr) = self.status.reference_hfr {
+            writeln!(status, "reference hfr: {:.2}", reference_hfr)?;
+        }
+        if let Some(transform) = self.status.last_transform {
+            writeln!(
+                status,
+                "last offset: {:.1} {:.1} rotation: {:.2}° ({} stars matched)",
+                transform.dx,
+                transform.dy,
+                transform.rotation_degrees(),
+                self.status.last_matches
+            )?;
+        }
+        if let Some(ref rejection) = self.status.last_rejection {
+            writeln!(status, "last rejected: {}", rejection)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,214 @@
+use super::{histogram::Histogram, sample_bilinear, StackedImage};
+use crate::{
+    camera::{interface::ROIImage, CameraId},
+    Result, SendUserUpdate, UserUpdate,
+};
+use std::{
+    fmt::Write,
+    sync::{mpsc, Arc},
+    thread::spawn,
+    time::{Duration, Instant},
+};
+
+// planetary frames are small and come fast, so allow a few to queue up before dropping
+const QUEUE_LENGTH: usize = 16;
+
+#[derive(Clone, Debug, Default)]
+pub struct LuckyStatus {
+    pub seen: usize,
+    pub target: usize,
+    pub keep: usize,
+    pub best_score: Option<f64>,
+    pub worst_kept_score: Option<f64>,
+    pub stacked: Option<usize>,
+    pub error: Option<String>,
+    pub duration: Duration,
+}
+
+#[derive(Debug)]
+pub struct LuckyResult {
+    // only set once the run has finished
+    pub stack: Option<StackedImage>,
+    pub status: LuckyStatus,
+}
+
+// mean squared laplacian: sharp detail has large second derivatives, blur smooths them away
+fn sharpness(image: &ROIImage) -> f64 {
+    let img = &image.image;
+    let (width, height) = img.size;
+    if width < 3 || height < 3 {
+        return 0.0;
+    }
+    let mut sum = 0.0;
+    for y in 1..height - 1 {
+        for x in 1..width - 1 {
+            let value = |x, y| f64::from(img[(x, y)]);
+            let laplacian = 4.0 * value(x, y)
+                - value(x - 1, y)
+                - value(x + 1, y)
+                - value(x, y - 1)
+                - value(x, y + 1);
+            sum += laplacian * laplacian;
+        }
+    }
+    sum / ((width - 2) * (height - 2)) as f64
+}
+
+// brightness weighted center, above the median so the sky doesn't pull it to the middle
+fn center_of_mass(image: &Arc<ROIImage>) -> (f64, f64) {
+    let background = f64::from(Histogram::compute(image).median());
+    let width = image.image.size.0;
+    let (mut x, mut y, mut total) = (0.0, 0.0, 0.0);
+    for (index, &value) in image.image.data().iter().enumerate() {
+        let weight = (f64::from(value) - background).max(0.0);
+        x += (index % width) as f64 * weight;
+        y += (index / width) as f64 * weight;
+        total += weight;
+    }
+    if total > 0.0 {
+        (x / total, y / total)
+    } else {
+        (0.0, 0.0)
+    }
+}
+
+// frames are sorted sharpest first, and aligned onto the sharpest by center of mass
+fn stack_frames(frames: &[(f64, Arc<ROIImage>)]) -> Result<StackedImage> {
+    let reference = &frames.first().ok_or("No frames to stack")?.1;
+    let size = reference.image.size;
+    let reference_center = center_of_mass(reference);
+    let mut sum = vec![0.0; size.0 * size.1];
+    let mut count = vec![0u32; size.0 * size.1];
+    for (_, frame) in frames {
+        if frame.image.size != size {
+            continue;
+        }
+        let center = center_of_mass(frame);
+        let shift = (center.0 - reference_center.0, center.1 - reference_center.1);
+        for (index, (sum, count)) in sum.iter_mut().zip(count.iter_mut()).enumerate() {
+            let (x, y) = ((index % size.0) as f64, (index / size.0) as f64);
+            if let Some(value) = sample_bilinear(&frame.image, x + shift.0, y + shift.1) {
+                *sum += value;
+                *count += 1;
+            }
+        }
+    }
+    Ok(StackedImage::average(&sum, &count, reference))
+}
+
+/// Scores a run of frames by sharpness on its own thread, keeps the best ones and averages
+/// them once the run is complete.
+pub struct Lucky {
+    send: mpsc::SyncSender<Arc<ROIImage>>,
+    status: LuckyStatus,
+    dropped: u64,
+}
+
+impl Lucky {
+    // keep is the fraction of frames stacked, from 0 to 1
+    pub fn new(send_user_update: SendUserUpdate, id: CameraId, target: usize, keep: f64) -> Self {
+        let keep = ((target as f64 * keep).ceil() as usize).max(1).min(target);
+        let (send, recv) = mpsc::sync_channel::<Arc<ROIImage>>(QUEUE_LENGTH);
+        let status = LuckyStatus {
+            target,
+            keep,
+            ..LuckyStatus::default()
+        };
+        let mut thread_status = status.clone();
+        spawn(move || {
+            let mut best = Vec::with_capacity(keep + 1);
+            while let Ok(frame) = recv.recv() {
+                let score = sharpness(&frame);
+                // a frame that can't be scored still counts towards the run, but isn't kept
+                if score.is_finite() {
+                    best.push((score, frame));
+                    best.sort_by(|l, r| r.0.total_cmp(&l.0));
+                    best.truncate(keep);
+                }
+                thread_status.seen += 1;
+                thread_status.best_score = best.first().map(|&(score, _)| score);
+                thread_status.worst_kept_score = best.last().map(|&(score, _)| score);
+                let done = thread_status.seen >= target;
+                let stack = if done {
+                    let begin = Instant::now();
+                    let stack = match stack_frames(&best) {
+                        Ok(stack) => {
+                            thread_status.stacked = Some(best.len());
+                            Some(stack)
+                        }
+                        Err(err) => {
+                            thread_status.error = Some(err.to_string());
+                            None
+                        }
+                    };
+                    thread_status.duration = Instant::now() - begin;
+                    stack
+                } else {
+                    None
+                };
+                let result = LuckyResult {
+                    stack,
+                    status: thread_status.clone(),
+                };
+                if send_user_update
+                    .send_event(UserUpdate::LuckyResult(id, result))
+                    .is_err()
+                    || done
+                {
+                    break;
+                }
+            }
+        });
+        Self {
+            send,
+            status,
+            dropped: 0,
+        }
+    }
+
+    pub fn add(&mut self, frame: Arc<ROIImage>) {
+        match self.send.try_send(frame) {
+            Ok(()) => (),
+            Err(mpsc::TrySendError::Full(_)) => self.dropped += 1,
+            // the run is complete, later frames aren't needed
+            Err(mpsc::TrySendError::Disconnected(_)) => (),
+        }
+    }
+
+    pub fn done(&self) -> bool {
+        self.status.stacked.is_some() || self.status.error.is_some()
+    }
+
+    pub fn user_update(&mut self, status: LuckyStatus) {
+        self.status = status;
+    }
+
+    pub fn status(&self, status: &mut String) -> Result<()> {
+        writeln!(
+            status,
+            "lucky: {}/{} frames, keeping best {} ({} dropped)",
+            self.status.seen, self.status.target, self.status.keep, self.dropped
+        )?;
+        if let (Some(best), Some(worst)) = (self.status.best_score, self.status.worst_kept_score) {
+            writeln!(
+                status,
+                "sharpness: best {:.1} worst kept {:.1}",
+                best, worst
+            )?;
+        }
+        if let Some(stacked) = self.status.stacked {
+            writeln!(
+                status,
+                "lucky stack of {} frames done ({:?})",
+                stacked, self.status.duration
+            )?;
+        }
+        if let Some(ref error) = self.status.error {
+            writeln!(status, "lucky error: {}", error)?;
+        }
+        if self.done() {
+            writeln!(status, "live view paused, lucky stop to resume")?;
+        }
+        Ok(())
+    }
+}
@@ -1,7 +1,17 @@
+use crate::camera::interface::ROIImage;
 use khygl::texture::{offset, CpuTexture};
+use std::sync::Arc;
 
+pub mod colormap;
+pub mod combine;
+pub mod defects;
+pub mod histogram;
+pub mod livestack;
+pub mod lucky;
 pub mod process;
-mod starfinder;
+pub mod register;
+pub mod starfinder;
+pub mod stretch;
 
 pub fn median(seq: &mut [f64]) -> f64 {
     seq.sort_unstable_by(|l, r| l.partial_cmp(&r).unwrap());
@@ -82,6 +92,65 @@ pub fn f64_to_u8(mut value: f64) -> u8 {
     }
 }
 
+// None outside the image
+pub fn sample_bilinear(image: &CpuTexture<u16>, x: f64, y: f64) -> Option<f64> {
+    if x < 0.0 || y < 0.0 {
+        return None;
+    }
+    let (x0, y0) = (x as usize, y as usize);
+    if x0 + 1 >= image.size.0 || y0 + 1 >= image.size.1 {
+        return None;
+    }
+    let (fx, fy) = (x - x0 as f64, y - y0 as f64);
+    let value = |x, y| f64::from(image[(x, y)]);
+    let top = value(x0, y0) * (1.0 - fx) + value(x0 + 1, y0) * fx;
+    let bottom = value(x0, y0 + 1) * (1.0 - fx) + value(x0 + 1, y0 + 1) * fx;
+    Some(top * (1.0 - fy) + bottom * fy)
+}
+
+/// An average of frames at full precision, for saving, along with a copy rounded to u16 for
+/// display and processing.
+#[derive(Debug)]
+pub struct StackedImage {
+    pub pixels: Vec<f32>,
+    pub size: (usize, usize),
+    // with the metadata of the reference frame
+    pub image: Arc<ROIImage>,
+}
+
+impl StackedImage {
+    // pixels no frame covered are 0
+    pub fn average(sum: &[f64], count: &[u32], reference: &ROIImage) -> Self {
+        let pixels = sum
+            .iter()
+            .zip(count.iter())
+            .map(|(&sum, &count)| {
+                if count == 0 {
+                    0.0
+                } else {
+                    (sum / f64::from(count)) as f32
+                }
+            })
+            .collect::<Vec<_>>();
+        let rounded = pixels
+            .iter()
+            .map(|&value| value.round().max(0.0).min(f32::from(u16::max_value())) as u16)
+            .collect();
+        let size = reference.image.size;
+        let image = Arc::new(ROIImage {
+            image: CpuTexture::new(rounded, size),
+            location: reference.location.clone(),
+            original: reference.original.clone(),
+            meta: reference.meta.clone(),
+        });
+        Self {
+            pixels,
+            size,
+            image,
+        }
+    }
+}
+
 fn floodfind_one<T: Copy>(
     img: &CpuTexture<T>,
     condition: &impl Fn(T) -> bool,
@@ -1,6 +1,13 @@
-// use super::starfinder::{find_stars, Star};
-use crate::{camera::interface::ROIImage, Result, SendUserUpdate, UserUpdate};
-use khygl::texture::CpuTexture;
+use super::{
+    histogram::Histogram,
+    median,
+    starfinder::{find_stars, Background, Star},
+    stretch::{asinh_stretch, gamma_stretch, log_stretch, lookup_table, ScreenTransfer},
+};
+use crate::{
+    camera::{interface::ROIImage, CameraId},
+    Result, SendUserUpdate, UserUpdate,
+};
 use std::{
     fmt::Write,
     sync::{mpsc, Arc},
@@ -8,30 +15,16 @@ use std::{
     time::{Duration, Instant},
 };
 
-fn mean(data: &[u16]) -> f64 {
-    let mut sum = 0;
-    for &datum in data {
-        sum += u64::from(datum);
-    }
-    sum as f64 / data.len() as f64
-}
-
-fn stdev(data: &[u16], mean: f64) -> f64 {
-    let mut sum = 0.0;
-    for &datum in data {
-        let diff = mean - f64::from(datum);
-        sum += diff * diff;
-    }
-    (sum / data.len() as f64).sqrt()
-}
-
 #[derive(Debug)]
 pub struct ProcessResult {
-    sorted: Vec<u16>,
     mean: f64,
     stdev: f64,
+    histogram: Histogram,
+    background: Background,
     duration: Duration,
-    // stars: Vec<Star>,
+    // star detection is much slower than the histogram, so it's timed on its own
+    star_duration: Duration,
+    stars: Vec<Star>,
 }
 
 fn u16_to_f64(val: u16) -> f64 {
@@ -39,33 +32,41 @@ fn u16_to_f64(val: u16) -> f64 {
 }
 
 impl ProcessResult {
-    fn compute(image: &CpuTexture<u16>) -> Self {
+    fn compute(image: &Arc<ROIImage>) -> Self {
         let begin = Instant::now();
-        let mut sorted = image.data().to_vec();
-        sorted.sort_unstable();
-        let mean = mean(&sorted);
-        let stdev = stdev(&sorted, mean);
-        // let stars = find_stars(image, mean, stdev);
+        let histogram = Histogram::compute(image);
+        let mean = histogram.mean();
+        let stdev = histogram.stdev(mean);
+        let median = histogram.median();
+        let background = Background::from_median_mad(
+            f64::from(median),
+            histogram.median_absolute_deviation(median),
+        );
         let duration = Instant::now() - begin;
+        let begin = Instant::now();
+        let stars = find_stars(&image.image);
+        let star_duration = Instant::now() - begin;
         Self {
-            sorted,
             mean,
             stdev,
+            histogram,
+            background,
             duration,
-            // stars,
+            star_duration,
+            stars,
         }
     }
 
     fn get_clip_median(&self, clip_perc: f64) -> (u16, u16) {
-        let len = self.sorted.len() as f64;
+        let len = self.histogram.total() as f64;
         let clip_index = (clip_perc * len).max(0.0).min(len - 1.0);
         let clip = if clip_perc == 0.0 {
             0
         } else {
-            self.sorted[clip_index as usize]
+            self.histogram.value_at(clip_index as u64)
         };
         let median_index = (clip_index + (len - 1.0)) / 2.0;
-        let median = self.sorted[median_index as usize];
+        let median = self.histogram.value_at(median_index as u64);
         (clip, median)
     }
 
@@ -88,6 +89,21 @@ impl ProcessResult {
         (scale, offset)
     }
 
+    fn screen_transfer(
+        &self,
+        shadows_sigma: f64,
+        target_background: f64,
+        highlights: f64,
+    ) -> ScreenTransfer {
+        ScreenTransfer::auto(
+            u16_to_f64(self.histogram.median()),
+            self.background.noise / f64::from(u16::max_value()),
+            shadows_sigma,
+            target_background,
+            highlights,
+        )
+    }
+
     fn mean_scale_offset(&self, sigma: f64, mean_location: f64) -> (f64, f64) {
         // y = (x - mean) / (stdev * sigma) + mean_location
         // y = x * 1 / (stdev * sigma) - mean / (stdev * sigma) + mean_location
@@ -99,10 +115,61 @@ impl ProcessResult {
     }
 }
 
+// the stf is recomputed from every frame's noise estimate, which wobbles by far less than this
+const CURVE_TOLERANCE: f64 = 1e-3;
+
+#[derive(Clone, Copy, PartialEq)]
 enum ProcessorType {
     Median,
     Mean,
     Linear,
+    Stf,
+    Asinh,
+    Log,
+    Gamma,
+    Equalize,
+}
+
+impl ProcessorType {
+    fn name(&self) -> &'static str {
+        match self {
+            ProcessorType::Median => "median",
+            ProcessorType::Mean => "mean",
+            ProcessorType::Linear => "linear",
+            ProcessorType::Stf => "stf",
+            ProcessorType::Asinh => "asinh",
+            ProcessorType::Log => "log",
+            ProcessorType::Gamma => "gamma",
+            ProcessorType::Equalize => "equalize",
+        }
+    }
+}
+
+// what a curve was built from, so it's only rebuilt (and the image uploaded again) when the
+// stretch visibly changes
+#[derive(Clone, Copy)]
+struct CurveKey {
+    processor_type: ProcessorType,
+    stf: ScreenTransfer,
+    softening: f64,
+    log_scale: f64,
+    gamma: f64,
+}
+
+impl CurveKey {
+    #[allow(clippy::float_cmp)]
+    fn same_curve(&self, other: &CurveKey) -> bool {
+        let close = |l: f64, r: f64| (l - r).abs() < CURVE_TOLERANCE;
+        // equalization follows the whole histogram, not just the stf
+        self.processor_type == other.processor_type
+            && self.processor_type != ProcessorType::Equalize
+            && close(self.stf.shadows, other.stf.shadows)
+            && close(self.stf.midtones, other.stf.midtones)
+            && close(self.stf.highlights, other.stf.highlights)
+            && self.softening == other.softening
+            && self.log_scale == other.log_scale
+            && self.gamma == other.gamma
+    }
 }
 
 pub struct Processor {
@@ -118,14 +185,32 @@ pub struct Processor {
 
     scale: f64,
     offset: f64,
+
+    shadows_sigma: f64,
+    target_background: f64,
+    highlights: f64,
+
+    softening: f64,
+    log_scale: f64,
+    gamma: f64,
+
+    // lookup table for the current non-linear stretch, rebuilt when the result or settings change
+    curve: Option<Arc<Vec<u16>>>,
+    curve_key: Option<CurveKey>,
+
+    show_histogram: bool,
+    histogram_log: bool,
+
+    // frames that arrived while the previous one was still being processed
+    dropped: u64,
 }
 
 impl Processor {
-    pub fn new(send_user_update: SendUserUpdate) -> Self {
+    pub fn new(send_user_update: SendUserUpdate, id: CameraId) -> Self {
         let (send, recv) = mpsc::sync_channel::<Arc<ROIImage>>(1);
         spawn(move || {
             while let Ok(img) = recv.recv() {
-                let result = UserUpdate::ProcessResult(ProcessResult::compute(&img.image));
+                let result = UserUpdate::ProcessResult(id, ProcessResult::compute(&img));
                 if send_user_update.send_event(result).is_err() {
                     break;
                 }
@@ -144,14 +229,33 @@ impl Processor {
 
             scale: 1.0,
             offset: 0.0,
+
+            shadows_sigma: -2.8,
+            target_background: 0.25,
+            highlights: 1.0,
+
+            softening: 0.05,
+            log_scale: 1000.0,
+            gamma: 2.2,
+
+            curve: None,
+            curve_key: None,
+
+            show_histogram: true,
+            histogram_log: true,
+
+            dropped: 0,
         }
     }
 
     // true if ok, false if dropped frame
-    pub fn process(&self, image: Arc<ROIImage>) -> Result<bool> {
+    pub fn process(&mut self, image: Arc<ROIImage>) -> Result<bool> {
         match self.send.try_send(image) {
             Ok(()) => Ok(true),
-            Err(mpsc::TrySendError::Full(_)) => Ok(false),
+            Err(mpsc::TrySendError::Full(_)) => {
+                self.dropped += 1;
+                Ok(false)
+            }
             Err(mpsc::TrySendError::Disconnected(_)) => {
                 Err("Processing thread disconnected".into())
             }
@@ -173,6 +277,14 @@ impl Processor {
             ["median"] => self.processor_type = ProcessorType::Median,
             ["mean"] => self.processor_type = ProcessorType::Mean,
             ["linear"] => self.processor_type = ProcessorType::Linear,
+            ["stf"] => self.processor_type = ProcessorType::Stf,
+            ["asinh"] => self.processor_type = ProcessorType::Asinh,
+            ["log"] => self.processor_type = ProcessorType::Log,
+            ["gamma"] => self.processor_type = ProcessorType::Gamma,
+            ["equalize"] => self.processor_type = ProcessorType::Equalize,
+            ["histogram"] => self.show_histogram = !self.show_histogram,
+            ["histogram", "log"] => self.histogram_log = true,
+            ["histogram", "linear"] => self.histogram_log = false,
             [key, value] => {
                 let ok = parse(key, value, "clip", &mut self.clip, true)
                     || parse(
@@ -185,18 +297,38 @@ impl Processor {
                     || parse(key, value, "sigma", &mut self.sigma, false)
                     || parse(key, value, "mean_location", &mut self.mean_location, true)
                     || parse(key, value, "scale", &mut self.scale, false)
-                    || parse(key, value, "offset", &mut self.offset, false);
+                    || parse(key, value, "offset", &mut self.offset, false)
+                    || parse(key, value, "shadows_sigma", &mut self.shadows_sigma, false)
+                    || parse(
+                        key,
+                        value,
+                        "target_background",
+                        &mut self.target_background,
+                        true,
+                    )
+                    || parse(key, value, "highlights", &mut self.highlights, true)
+                    || parse(key, value, "softening", &mut self.softening, false)
+                    || parse(key, value, "log_scale", &mut self.log_scale, false)
+                    || parse(key, value, "gamma", &mut self.gamma, false);
+                if ok {
+                    self.update_curve();
+                }
                 return Ok(ok);
             }
             _ => return Ok(false),
         }
+        self.update_curve();
         Ok(true)
     }
 
     pub fn status(&self, status: &mut String) -> Result<()> {
+        writeln!(
+            status,
+            "process: {} (median, mean, linear, stf, asinh, log, gamma, equalize)",
+            self.processor_type.name()
+        )?;
         match self.processor_type {
             ProcessorType::Median => {
-                writeln!(status, "process: median (mean, linear)")?;
                 writeln!(
                     status,
                     "clip: {}% median_location: {}%",
@@ -205,7 +337,6 @@ impl Processor {
                 )?;
             }
             ProcessorType::Mean => {
-                writeln!(status, "process: mean (median, linear)")?;
                 writeln!(
                     status,
                     "sigma: {} mean_location: {}%",
@@ -213,10 +344,43 @@ impl Processor {
                     self.mean_location * 100.0,
                 )?;
             }
-            ProcessorType::Linear => {
-                writeln!(status, "process: linear (median, mean)")?;
+            ProcessorType::Stf => {
+                writeln!(
+                    status,
+                    "shadows_sigma: {} target_background: {}% highlights: {}%",
+                    self.shadows_sigma,
+                    self.target_background * 100.0,
+                    self.highlights * 100.0,
+                )?;
+                if let Some(stf) = self.screen_transfer() {
+                    writeln!(
+                        status,
+                        "(shadows: {:.4} midtones: {:.4} highlights: {:.4})",
+                        stf.shadows, stf.midtones, stf.highlights
+                    )?;
+                }
             }
+            ProcessorType::Asinh | ProcessorType::Log | ProcessorType::Gamma => {
+                writeln!(
+                    status,
+                    "shadows_sigma: {} highlights: {}%",
+                    self.shadows_sigma,
+                    self.highlights * 100.0,
+                )?;
+                writeln!(
+                    status,
+                    "softening: {} log_scale: {} gamma: {}",
+                    self.softening, self.log_scale, self.gamma
+                )?;
+            }
+            ProcessorType::Linear | ProcessorType::Equalize => (),
         }
+        writeln!(
+            status,
+            "histogram|histogram [log|linear]: {} ({})",
+            self.show_histogram,
+            if self.histogram_log { "log" } else { "linear" }
+        )?;
         if let Some(ref process_result) = self.process_result {
             let (_, median) = process_result.get_clip_median(0.0);
             let (scale, offset) = self
@@ -238,8 +402,39 @@ impl Processor {
             )?;
             writeln!(
                 status,
-                "image processing time: {:?}",
-                process_result.duration
+                "background: {:.1} noise: {:.1}",
+                process_result.background.level, process_result.background.noise
+            )?;
+            let stars = &process_result.stars;
+            let unsaturated = stars.iter().filter(|star| !star.saturated);
+            let mut hfr = unsaturated.clone().map(|star| star.hfr).collect::<Vec<_>>();
+            let mut fwhm = unsaturated
+                .clone()
+                .map(|star| star.fwhm)
+                .collect::<Vec<_>>();
+            let mut eccentricity = unsaturated
+                .map(|star| star.eccentricity)
+                .collect::<Vec<_>>();
+            writeln!(
+                status,
+                "stars: {} ({} saturated)",
+                stars.len(),
+                stars.len() - hfr.len()
+            )?;
+            // saturated stars have flattened cores, so they'd skew the size measurements
+            if !hfr.is_empty() {
+                writeln!(
+                    status,
+                    "median hfr: {:.2} fwhm: {:.2} eccentricity: {:.2}",
+                    median(&mut hfr),
+                    median(&mut fwhm),
+                    median(&mut eccentricity)
+                )?;
+            }
+            writeln!(
+                status,
+                "image processing time: {:?} star detection: {:?} (dropped frames: {})",
+                process_result.duration, process_result.star_duration, self.dropped
             )?;
         }
         Ok(())
@@ -247,6 +442,70 @@ impl Processor {
 
     pub fn user_update(&mut self, process_result: ProcessResult) {
         self.process_result = Some(process_result);
+        self.update_curve();
+    }
+
+    fn screen_transfer(&self) -> Option<ScreenTransfer> {
+        let process_result = self.process_result.as_ref()?;
+        Some(process_result.screen_transfer(
+            self.shadows_sigma,
+            self.target_background,
+            self.highlights,
+        ))
+    }
+
+    fn curve_key(&self) -> Option<CurveKey> {
+        Some(CurveKey {
+            processor_type: self.processor_type,
+            stf: self.screen_transfer()?,
+            softening: self.softening,
+            log_scale: self.log_scale,
+            gamma: self.gamma,
+        })
+    }
+
+    // keeps the previous curve, and so the same Arc, if it would come out the same
+    fn update_curve(&mut self) {
+        let key = self.curve_key();
+        let unchanged = match (&key, &self.curve_key) {
+            (Some(key), Some(old)) => key.same_curve(old),
+            (None, None) => true,
+            _ => false,
+        };
+        if !unchanged {
+            self.curve = self.build_curve().map(Arc::new);
+            self.curve_key = key;
+        }
+    }
+
+    fn build_curve(&self) -> Option<Vec<u16>> {
+        let process_result = self.process_result.as_ref()?;
+        // the other curves start from the same black and white points as the stf, so the
+        // background noise isn't blown up into the whole display range
+        let stf = self.screen_transfer()?;
+        let normalize = move |x: f64| {
+            let range = stf.highlights - stf.shadows;
+            if range > 0.0 {
+                ((x - stf.shadows) / range).max(0.0).min(1.0)
+            } else {
+                0.0
+            }
+        };
+        let (softening, log_scale, gamma) = (self.softening, self.log_scale, self.gamma);
+        let curve = match self.processor_type {
+            ProcessorType::Median | ProcessorType::Mean | ProcessorType::Linear => return None,
+            ProcessorType::Stf => lookup_table(|x| stf.apply(x)),
+            ProcessorType::Asinh => lookup_table(|x| asinh_stretch(softening, normalize(x))),
+            ProcessorType::Log => lookup_table(|x| log_stretch(log_scale, normalize(x))),
+            ProcessorType::Gamma => lookup_table(|x| gamma_stretch(gamma, normalize(x))),
+            ProcessorType::Equalize => process_result.histogram.equalization(),
+        };
+        Some(curve)
+    }
+
+    // None if the current stretch is linear and done by scale/offset alone
+    pub fn get_curve(&self) -> Option<Arc<Vec<u16>>> {
+        self.curve.clone()
     }
 
     pub fn get_scale_offset(&self) -> Option<(f64, f64)> {
@@ -257,11 +516,45 @@ impl Processor {
             }
             ProcessorType::Mean => process_result.mean_scale_offset(self.sigma, self.mean_location),
             ProcessorType::Linear => (self.scale, self.offset),
+            // the curve has already been applied to the image
+            ProcessorType::Stf
+            | ProcessorType::Asinh
+            | ProcessorType::Log
+            | ProcessorType::Gamma
+            | ProcessorType::Equalize => (1.0, 0.0),
         };
         Some(result)
     }
 
-    // pub fn get_stars(&self) -> Option<&[Star]> {
-    //     Some(&self.process_result.as_ref()?.stars)
-    // }
+    // bar heights, and the black and white points of the current stretch, all from 0 to 1
+    pub fn get_histogram(&self) -> Option<(Vec<f64>, (f64, f64))> {
+        if !self.show_histogram {
+            return None;
+        }
+        let heights = self
+            .process_result
+            .as_ref()?
+            .histogram
+            .heights(self.histogram_log);
+        let black_white = match self.processor_type {
+            ProcessorType::Stf
+            | ProcessorType::Asinh
+            | ProcessorType::Log
+            | ProcessorType::Gamma => {
+                let stf = self.screen_transfer()?;
+                (stf.shadows, stf.highlights)
+            }
+            ProcessorType::Equalize => (0.0, 1.0),
+            _ => {
+                // displayed = value * scale + offset, solved for displayed = 0 and 1
+                let (scale, offset) = self.get_scale_offset()?;
+                (-offset / scale, (1.0 - offset) / scale)
+            }
+        };
+        Some((heights, black_white))
+    }
+
+    pub fn get_stars(&self) -> Option<&[Star]> {
+        Some(&self.process_result.as_ref()?.stars)
+    }
 }
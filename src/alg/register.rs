@@ -0,0 +1,194 @@
+// Registration of one star field onto another, by rotation and translation.
+//
+// Algorithm:
+//
+// 1) Build every triangle out of the brightest stars in both fields. The ratios of a
+//    triangle's sides don't change under rotation and translation.
+// 2) Triangles with matching ratios vote for their corresponding vertices.
+// 3) Out of the most voted star pairs, find the pair of pairs whose transform agrees with the
+//    most other pairs, then refine it by least squares over all the pairs that agree.
+
+// only the brightest stars are used, triangles grow with the cube of this
+pub const MATCH_STARS: usize = 20;
+// fewest star pairs that must agree for a registration to be trusted
+pub const MIN_MATCHES: usize = 6;
+const RATIO_TOLERANCE: f64 = 0.01;
+// triangles with a side shorter than this (pixels) are too sensitive to centroid noise
+const MIN_SIDE: f64 = 5.0;
+// a star pair agrees with a transform if it lands within this many pixels
+const INLIER_DISTANCE: f64 = 2.0;
+// most voted pairs tried as the seed of a transform
+const SEED_PAIRS: usize = 12;
+
+/// Rotation followed by translation.
+#[derive(Clone, Copy, Debug)]
+pub struct Transform {
+    pub cos: f64,
+    pub sin: f64,
+    pub dx: f64,
+    pub dy: f64,
+}
+
+impl Transform {
+    pub fn apply(&self, point: (f64, f64)) -> (f64, f64) {
+        (
+            self.cos * point.0 - self.sin * point.1 + self.dx,
+            self.sin * point.0 + self.cos * point.1 + self.dy,
+        )
+    }
+
+    pub fn inverse(&self) -> Self {
+        Self {
+            cos: self.cos,
+            sin: -self.sin,
+            dx: -(self.cos * self.dx + self.sin * self.dy),
+            dy: -(-self.sin * self.dx + self.cos * self.dy),
+        }
+    }
+
+    pub fn rotation_degrees(&self) -> f64 {
+        self.sin.atan2(self.cos).to_degrees()
+    }
+
+    // least squares fit of the transform taking each from point to its to point
+    fn fit(pairs: &[((f64, f64), (f64, f64))]) -> Self {
+        let count = pairs.len() as f64;
+        let (mut from_center, mut to_center) = ((0.0, 0.0), (0.0, 0.0));
+        for &(from, to) in pairs {
+            from_center = (
+                from_center.0 + from.0 / count,
+                from_center.1 + from.1 / count,
+            );
+            to_center = (to_center.0 + to.0 / count, to_center.1 + to.1 / count);
+        }
+        let (mut cross, mut dot) = (0.0, 0.0);
+        for &(from, to) in pairs {
+            let from = (from.0 - from_center.0, from.1 - from_center.1);
+            let to = (to.0 - to_center.0, to.1 - to_center.1);
+            cross += from.0 * to.1 - from.1 * to.0;
+            dot += from.0 * to.0 + from.1 * to.1;
+        }
+        let angle = cross.atan2(dot);
+        let (sin, cos) = angle.sin_cos();
+        Self {
+            cos,
+            sin,
+            dx: to_center.0 - (cos * from_center.0 - sin * from_center.1),
+            dy: to_center.1 - (sin * from_center.0 + cos * from_center.1),
+        }
+    }
+}
+
+struct Triangle {
+    // vertex i is opposite the i'th shortest side
+    vertices: [usize; 3],
+    // shortest and middle side, over the longest
+    ratios: (f64, f64),
+}
+
+fn distance(a: (f64, f64), b: (f64, f64)) -> f64 {
+    ((a.0 - b.0) * (a.0 - b.0) + (a.1 - b.1) * (a.1 - b.1)).sqrt()
+}
+
+fn triangles(points: &[(f64, f64)]) -> Vec<Triangle> {
+    let mut result = Vec::new();
+    for i in 0..points.len() {
+        for j in i + 1..points.len() {
+            for k in j + 1..points.len() {
+                let mut sides = [
+                    (distance(points[j], points[k]), i),
+                    (distance(points[i], points[k]), j),
+                    (distance(points[i], points[j]), k),
+                ];
+                sides.sort_by(|l, r| l.0.total_cmp(&r.0));
+                if sides[0].0 < MIN_SIDE {
+                    continue;
+                }
+                result.push(Triangle {
+                    vertices: [sides[0].1, sides[1].1, sides[2].1],
+                    ratios: (sides[0].0 / sides[2].0, sides[1].0 / sides[2].0),
+                });
+            }
+        }
+    }
+    result
+}
+
+// star pairs (from index, to index) with their votes, most voted first, each star used once
+fn vote(from: &[(f64, f64)], to: &[(f64, f64)]) -> Vec<(usize, usize)> {
+    let mut votes = vec![vec![0usize; to.len()]; from.len()];
+    let to_triangles = triangles(to);
+    for from_triangle in triangles(from) {
+        for to_triangle in &to_triangles {
+            if (from_triangle.ratios.0 - to_triangle.ratios.0).abs() < RATIO_TOLERANCE
+                && (from_triangle.ratios.1 - to_triangle.ratios.1).abs() < RATIO_TOLERANCE
+            {
+                for vertex in 0..3 {
+                    votes[from_triangle.vertices[vertex]][to_triangle.vertices[vertex]] += 1;
+                }
+            }
+        }
+    }
+    let mut candidates = Vec::new();
+    for (from_index, row) in votes.iter().enumerate() {
+        for (to_index, &count) in row.iter().enumerate() {
+            if count > 0 {
+                candidates.push((count, from_index, to_index));
+            }
+        }
+    }
+    candidates.sort_by_key(|&(count, _, _)| std::cmp::Reverse(count));
+    let (mut used_from, mut used_to) = (vec![false; from.len()], vec![false; to.len()]);
+    let mut pairs = Vec::new();
+    for (_, from_index, to_index) in candidates {
+        if !used_from[from_index] && !used_to[to_index] {
+            used_from[from_index] = true;
+            used_to[to_index] = true;
+            pairs.push((from_index, to_index));
+        }
+    }
+    pairs
+}
+
+/// Finds the transform taking the from points onto the to points, along with how many star
+/// pairs agree with it. Both should be sorted brightest first.
+pub fn register(from: &[(f64, f64)], to: &[(f64, f64)]) -> Option<(Transform, usize)> {
+    let from = &from[..from.len().min(MATCH_STARS)];
+    let to = &to[..to.len().min(MATCH_STARS)];
+    let pairs = vote(from, to)
+        .into_iter()
+        .map(|(from_index, to_index)| (from[from_index], to[to_index]))
+        .collect::<Vec<_>>();
+    let inliers = |transform: &Transform| {
+        pairs
+            .iter()
+            .cloned()
+            .filter(|&(from, to)| distance(transform.apply(from), to) < INLIER_DISTANCE)
+            .collect::<Vec<_>>()
+    };
+    let seeds = &pairs[..pairs.len().min(SEED_PAIRS)];
+    let mut best: Option<Vec<_>> = None;
+    for (index, &first) in seeds.iter().enumerate() {
+        for &second in &seeds[index + 1..] {
+            // a rotation keeps distances, so pairs of pairs that don't can't both be right
+            let from_distance = distance(first.0, second.0);
+            let to_distance = distance(first.1, second.1);
+            if from_distance < MIN_SIDE || (from_distance - to_distance).abs() > INLIER_DISTANCE {
+                continue;
+            }
+            let agreeing = inliers(&Transform::fit(&[first, second]));
+            if best
+                .as_ref()
+                .map_or(true, |best| agreeing.len() > best.len())
+            {
+                best = Some(agreeing);
+            }
+        }
+    }
+    let best = best?;
+    if best.len() < MIN_MATCHES {
+        return None;
+    }
+    let transform = Transform::fit(&best);
+    Some((transform, inliers(&transform).len()))
+}
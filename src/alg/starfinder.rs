@@ -1,14 +1,36 @@
-use super::floodfind;
-use khygl::texture::CpuTexture;
+use super::{floodfind, median};
+use khygl::texture::{offset, CpuTexture};
 
 /*
-Alternate algorithm:
+Algorithm:
 
-1) Find brightest pixel
-2) Flood fill out to brightest neighbor, until certain number of pixels in size, or threshhold is reached (median of surroundings?)
-3) Mask out star, goto 1, until N stars are found
+1) Estimate the background and noise per tile from the median and MAD, which stars barely
+   affect, and interpolate between tiles so gradients (moon, light pollution, vignetting)
+   follow the image
+2) Subtract the background, and flood fill connected regions above DETECTION_SIGMA * noise
+3) Split regions that contain several distinct peaks (deblending), by assigning each pixel
+   to its nearest peak
+4) Measure each star from its background subtracted pixels
 */
 
+// detection threshold, in units of background noise
+const DETECTION_SIGMA: f64 = 5.0;
+// MAD to standard deviation, for normally distributed noise
+const MAD_TO_SIGMA: f64 = 1.4826;
+const MIN_SIZE: usize = 5;
+const MAX_SIZE: usize = 5000;
+// a secondary peak is its own star if the dip between it and a brighter peak goes below this
+// fraction of its height above the background
+const DEBLEND_CONTRAST: f64 = 0.7;
+// 12 bit cameras top out at 0xfff0, 8 bit mode at 0xff00
+const SATURATION: u16 = 0xff00;
+// gaussian sigma to FWHM
+const SIGMA_TO_FWHM: f64 = 2.354_820_045;
+// background tiles are this many pixels across: several star widths, but small next to gradients
+const MESH_SIZE: usize = 64;
+// tile pixels further than this many sigma above the median are stars, and left out of the noise
+const MESH_CLIP_SIGMA: f64 = 3.0;
+
 #[derive(Debug)]
 pub struct Star {
     pub x: f64,
@@ -16,50 +38,281 @@ pub struct Star {
     // flux isn't *totally* correct, since the fringes of the star are cut off and not added
     pub flux: f64,
     pub hfr: f64,
+    pub fwhm: f64,
+    // 0 for round, approaching 1 for elongated
+    pub eccentricity: f64,
+    pub saturated: bool,
+}
+
+/// Background level and noise, both in ADU.
+#[derive(Clone, Copy, Debug)]
+pub struct Background {
+    pub level: f64,
+    pub noise: f64,
+}
+
+impl Background {
+    pub fn from_median_mad(median: f64, mad: f64) -> Self {
+        Self {
+            level: median,
+            // a perfectly flat synthetic background still needs a usable threshold
+            noise: (mad * MAD_TO_SIGMA).max(1.0),
+        }
+    }
+}
+
+// median and noise of one tile, with stars clipped out
+fn tile_background(values: &mut Vec<f64>) -> (f64, f64) {
+    let level = median(values);
+    let mut deviations = values
+        .iter()
+        .map(|value| (value - level).abs())
+        .collect::<Vec<_>>();
+    let noise = median(&mut deviations) * MAD_TO_SIGMA;
+    values.retain(|&value| value <= level + noise * MESH_CLIP_SIGMA);
+    if values.is_empty() {
+        return (level, noise);
+    }
+    let level = median(values);
+    deviations.clear();
+    deviations.extend(values.iter().map(|value| (value - level).abs()));
+    (level, median(&mut deviations) * MAD_TO_SIGMA)
 }
 
-impl Star {
-    pub fn new(x: f64, y: f64, flux: f64, hfr: f64) -> Self {
-        Self { x, y, flux, hfr }
+// each tile replaced by the median of it and its neighbors, so a tile covered by a bright star
+// or nebula is outvoted
+fn median_filter(grid: &[f64], tiles: (usize, usize)) -> Vec<f64> {
+    let mut filtered = Vec::with_capacity(grid.len());
+    let mut values = Vec::with_capacity(9);
+    for y in 0..tiles.1 {
+        for x in 0..tiles.0 {
+            values.clear();
+            for ny in y.saturating_sub(1)..(y + 2).min(tiles.1) {
+                for nx in x.saturating_sub(1)..(x + 2).min(tiles.0) {
+                    values.push(grid[ny * tiles.0 + nx]);
+                }
+            }
+            filtered.push(median(&mut values));
+        }
     }
+    filtered
 }
 
-pub fn find_stars(img: &CpuTexture<u16>, mean: f64, stdev: f64) -> Vec<Star> {
-    let noise_sigma = 3.0;
-    let floor = mean + stdev * noise_sigma;
-    let floor_u16 = floor as u16;
-    let min_size = 5;
-    let max_size = 100;
+/// Background level and noise across the image, from a mesh of tiles interpolated between
+/// tile centers.
+pub struct BackgroundMesh {
+    tiles: (usize, usize),
+    level: Vec<f64>,
+    noise: Vec<f64>,
+}
+
+impl BackgroundMesh {
+    pub fn compute(img: &CpuTexture<u16>) -> Self {
+        let tiles = (
+            ((img.size.0 + MESH_SIZE - 1) / MESH_SIZE).max(1),
+            ((img.size.1 + MESH_SIZE - 1) / MESH_SIZE).max(1),
+        );
+        let mut level = Vec::with_capacity(tiles.0 * tiles.1);
+        let mut noise = Vec::with_capacity(tiles.0 * tiles.1);
+        let mut values = Vec::with_capacity(MESH_SIZE * MESH_SIZE);
+        for tile_y in 0..tiles.1 {
+            for tile_x in 0..tiles.0 {
+                values.clear();
+                for y in tile_y * MESH_SIZE..((tile_y + 1) * MESH_SIZE).min(img.size.1) {
+                    for x in tile_x * MESH_SIZE..((tile_x + 1) * MESH_SIZE).min(img.size.0) {
+                        values.push(f64::from(img[(x, y)]));
+                    }
+                }
+                let (tile_level, tile_noise) = if values.is_empty() {
+                    (0.0, 0.0)
+                } else {
+                    tile_background(&mut values)
+                };
+                level.push(tile_level);
+                // a perfectly flat synthetic background still needs a usable threshold
+                noise.push(tile_noise.max(1.0));
+            }
+        }
+        Self {
+            level: median_filter(&level, tiles),
+            noise: median_filter(&noise, tiles),
+            tiles,
+        }
+    }
+
+    // bilinear between tile centers, and flat beyond the outermost ones
+    fn interpolate(&self, grid: &[f64], (x, y): (usize, usize)) -> f64 {
+        let to_grid = |coord: usize, tiles: usize| {
+            ((coord as f64 + 0.5) / MESH_SIZE as f64 - 0.5)
+                .max(0.0)
+                .min((tiles - 1) as f64)
+        };
+        let (gx, gy) = (to_grid(x, self.tiles.0), to_grid(y, self.tiles.1));
+        let (x0, y0) = (gx as usize, gy as usize);
+        let (x1, y1) = (
+            (x0 + 1).min(self.tiles.0 - 1),
+            (y0 + 1).min(self.tiles.1 - 1),
+        );
+        let (fx, fy) = (gx - x0 as f64, gy - y0 as f64);
+        let value = |x, y| grid[y * self.tiles.0 + x];
+        let top = value(x0, y0) * (1.0 - fx) + value(x1, y0) * fx;
+        let bottom = value(x0, y1) * (1.0 - fx) + value(x1, y1) * fx;
+        top * (1.0 - fy) + bottom * fy
+    }
+
+    pub fn level(&self, pixel: (usize, usize)) -> f64 {
+        self.interpolate(&self.level, pixel)
+    }
+
+    pub fn noise(&self, pixel: (usize, usize)) -> f64 {
+        self.interpolate(&self.noise, pixel)
+    }
+}
+
+// local maxima within a region, brightest first, filtered so that each is a distinct star. img
+// is background subtracted.
+fn find_peaks(img: &CpuTexture<f32>, region: &[(usize, usize)]) -> Vec<(usize, usize)> {
+    let mut candidates = region
+        .iter()
+        .cloned()
+        .filter(|&pixel| {
+            let value = img[pixel];
+            (-1..=1).all(|dy| {
+                (-1..=1).all(|dx| match offset(pixel, (dx, dy), img.size) {
+                    Some(neighbor) if neighbor != pixel => img[neighbor] <= value,
+                    _ => true,
+                })
+            })
+        })
+        .collect::<Vec<_>>();
+    candidates.sort_by(|&l, &r| img[r].total_cmp(&img[l]));
+    let mut peaks: Vec<(usize, usize)> = Vec::new();
+    for candidate in candidates {
+        let height = f64::from(img[candidate]);
+        let distinct = peaks.iter().all(|&peak| {
+            // sample along the line to the brighter peak, looking for a dip
+            let steps = ((peak.0 as f64 - candidate.0 as f64).abs())
+                .max((peak.1 as f64 - candidate.1 as f64).abs()) as usize;
+            if steps <= 1 {
+                return false;
+            }
+            let lowest = (1..steps)
+                .map(|step| {
+                    let t = step as f64 / steps as f64;
+                    let x = candidate.0 as f64 + (peak.0 as f64 - candidate.0 as f64) * t;
+                    let y = candidate.1 as f64 + (peak.1 as f64 - candidate.1 as f64) * t;
+                    f64::from(img[(x.round() as usize, y.round() as usize)])
+                })
+                .fold(f64::INFINITY, f64::min);
+            lowest < height * DEBLEND_CONTRAST
+        });
+        if distinct {
+            peaks.push(candidate);
+        }
+    }
+    peaks
+}
+
+// subtracted is the background subtracted image, raw is only checked for saturation
+fn measure(
+    raw: &CpuTexture<u16>,
+    subtracted: &CpuTexture<f32>,
+    pixels: &[(usize, usize)],
+) -> Option<Star> {
+    let mut sum = (0.0, 0.0);
+    let mut flux = 0.0;
+    let mut saturated = false;
+    for &pixel in pixels {
+        let value = f64::from(subtracted[pixel]).max(0.0);
+        sum = (
+            sum.0 + pixel.0 as f64 * value,
+            sum.1 + pixel.1 as f64 * value,
+        );
+        flux += value;
+        saturated |= raw[pixel] >= SATURATION;
+    }
+    if flux <= 0.0 {
+        return None;
+    }
+    let location = (sum.0 / flux, sum.1 / flux);
+    // https://en.wikipedia.org/wiki/Half_flux_diameter
+    let mut hfr_weird_sum = 0.0;
+    let (mut xx, mut yy, mut xy) = (0.0, 0.0, 0.0);
+    for &pixel in pixels {
+        let value = f64::from(subtracted[pixel]).max(0.0);
+        let dist_x = pixel.0 as f64 - location.0;
+        let dist_y = pixel.1 as f64 - location.1;
+        hfr_weird_sum += value * (dist_x * dist_x + dist_y * dist_y).sqrt();
+        xx += value * dist_x * dist_x;
+        yy += value * dist_y * dist_y;
+        xy += value * dist_x * dist_y;
+    }
+    let (xx, yy, xy) = (xx / flux, yy / flux, xy / flux);
+    // eigenvalues of the second moment matrix are the variances along the star's axes
+    let half_trace = (xx + yy) / 2.0;
+    let root = ((xx - yy) * (xx - yy) / 4.0 + xy * xy).sqrt();
+    let major = half_trace + root;
+    let minor = (half_trace - root).max(0.0);
+    let eccentricity = if major > 0.0 {
+        (1.0 - minor / major).sqrt()
+    } else {
+        0.0
+    };
+    Some(Star {
+        x: location.0,
+        y: location.1,
+        flux,
+        hfr: hfr_weird_sum / flux,
+        fwhm: SIGMA_TO_FWHM * half_trace.sqrt(),
+        eccentricity,
+        saturated,
+    })
+}
+
+pub fn find_stars(img: &CpuTexture<u16>) -> Vec<Star> {
+    let mesh = BackgroundMesh::compute(img);
+    let mut subtracted = Vec::with_capacity(img.size.0 * img.size.1);
+    // in units of the local noise, so one threshold works across the image
+    let mut significance = Vec::with_capacity(img.size.0 * img.size.1);
+    for y in 0..img.size.1 {
+        for x in 0..img.size.0 {
+            let value = f64::from(img[(x, y)]) - mesh.level((x, y));
+            subtracted.push(value as f32);
+            significance.push((value / mesh.noise((x, y))) as f32);
+        }
+    }
+    let subtracted = CpuTexture::new(subtracted, img.size);
+    let significance = CpuTexture::new(significance, img.size);
     let mut stars = Vec::new();
-    for star in floodfind(img, |v| v > floor_u16) {
-        if star.len() > max_size {
+    for region in floodfind(&significance, |v| f64::from(v) > DETECTION_SIGMA) {
+        if region.len() < MIN_SIZE || region.len() > MAX_SIZE {
             continue;
         }
-        if star.len() < min_size {
+        let peaks = find_peaks(&subtracted, &region);
+        if peaks.len() <= 1 {
+            stars.extend(measure(img, &subtracted, &region));
             continue;
         }
-        let mut sum = (0.0, 0.0);
-        let mut total_flux_above = 0.0;
-        for &pixel in &star {
-            let flux_above = img[pixel] as f64 - floor;
-            sum = (
-                sum.0 + pixel.0 as f64 * flux_above,
-                sum.1 + pixel.1 as f64 * flux_above,
-            );
-            total_flux_above += flux_above;
+        // deblend: each pixel belongs to its nearest peak
+        let mut split = vec![Vec::new(); peaks.len()];
+        for &pixel in &region {
+            let nearest = peaks
+                .iter()
+                .enumerate()
+                .min_by_key(|&(_, peak)| {
+                    let dx = pixel.0 as isize - peak.0 as isize;
+                    let dy = pixel.1 as isize - peak.1 as isize;
+                    dx * dx + dy * dy
+                })
+                .map(|(index, _)| index)
+                .expect("peaks is not empty");
+            split[nearest].push(pixel);
         }
-        let location = (sum.0 / total_flux_above, sum.1 / total_flux_above);
-        // https://en.wikipedia.org/wiki/Half_flux_diameter
-        let mut hfr_weird_sum = 0.0;
-        for pixel in star {
-            let flux_above = img[pixel] as f64 - floor;
-            let dist_x = pixel.0 as f64 - location.0;
-            let dist_y = pixel.1 as f64 - location.1;
-            let dist = (dist_x * dist_x + dist_y * dist_y).sqrt();
-            hfr_weird_sum += flux_above * dist;
+        for pixels in split {
+            if pixels.len() >= MIN_SIZE {
+                stars.extend(measure(img, &subtracted, &pixels));
+            }
         }
-        let hfr = hfr_weird_sum / total_flux_above;
-        stars.push(Star::new(location.0, location.1, total_flux_above, hfr));
     }
     stars
 }
@@ -0,0 +1,86 @@
+// Non-linear display curves. These can't be expressed as the renderer's scale/offset, so they
+// are baked into a lookup table that the display applies to each frame before uploading it.
+
+/// Maps every possible u16 value through a curve that takes and returns 0 to 1.
+pub fn lookup_table(curve: impl Fn(f64) -> f64) -> Vec<u16> {
+    let max = f64::from(u16::max_value());
+    (0..=u16::max_value())
+        .map(|value| {
+            let result = curve(f64::from(value) / max);
+            (result.max(0.0).min(1.0) * max).round() as u16
+        })
+        .collect()
+}
+
+// https://pixinsight.com/doc/tools/HistogramTransformation/HistogramTransformation.html
+// maps 0 to 0, midtones to 0.5 and 1 to 1
+pub fn midtones_transfer(midtones: f64, x: f64) -> f64 {
+    if x <= 0.0 {
+        0.0
+    } else if x >= 1.0 {
+        1.0
+    } else {
+        (midtones - 1.0) * x / ((2.0 * midtones - 1.0) * x - midtones)
+    }
+}
+
+/// PixInsight style screen transfer function: clip below shadows and above highlights, then
+/// bend the rest so that the midtones end up in the middle.
+#[derive(Clone, Copy, Debug)]
+pub struct ScreenTransfer {
+    pub shadows: f64,
+    pub midtones: f64,
+    pub highlights: f64,
+}
+
+impl ScreenTransfer {
+    // median and noise are 0 to 1, shadows_sigma is usually negative, clipping just below the
+    // background, and target_background is where the background ends up on screen
+    pub fn auto(
+        median: f64,
+        noise: f64,
+        shadows_sigma: f64,
+        target_background: f64,
+        highlights: f64,
+    ) -> Self {
+        let highlights = highlights.max(median).min(1.0);
+        let shadows = (median + shadows_sigma * noise).max(0.0).min(median);
+        let range = highlights - shadows;
+        let midtones = if range > 0.0 {
+            midtones_transfer(target_background, (median - shadows) / range)
+        } else {
+            0.5
+        };
+        Self {
+            shadows,
+            midtones,
+            highlights,
+        }
+    }
+
+    pub fn apply(&self, x: f64) -> f64 {
+        if x <= self.shadows {
+            0.0
+        } else if x >= self.highlights {
+            1.0
+        } else {
+            let normalized = (x - self.shadows) / (self.highlights - self.shadows);
+            midtones_transfer(self.midtones, normalized)
+        }
+    }
+}
+
+// small softening behaves like log near the background, large softening is nearly linear
+pub fn asinh_stretch(softening: f64, x: f64) -> f64 {
+    let softening = softening.max(1e-6);
+    (x / softening).asinh() / (1.0 / softening).asinh()
+}
+
+pub fn log_stretch(scale: f64, x: f64) -> f64 {
+    let scale = scale.max(1e-6);
+    (scale * x).ln_1p() / scale.ln_1p()
+}
+
+pub fn gamma_stretch(gamma: f64, x: f64) -> f64 {
+    x.max(0.0).powf(1.0 / gamma.max(0.01))
+}
@@ -0,0 +1,646 @@
+use crate::{
+    alg::{
+        combine::{combine, Rejection},
+        defects::DefectMap,
+    },
+    camera::{interface::ROIImage, CameraId},
+    fits::{self, ImageType, Observation},
+    library::{self, Entry, Library, DARK_DOUBLING_TEMPERATURE},
+    naming::{self, NameFields},
+    Result, SendUserUpdate, UserUpdate,
+};
+use khygl::texture::CpuTexture;
+use std::{
+    fmt::Write,
+    fs::{create_dir_all, read_to_string, remove_file, write},
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc},
+    thread::spawn,
+};
+
+// added after subtracting darks, so the noise around the background isn't clipped at zero
+const PEDESTAL: f64 = 1000.0;
+// flat pixels darker than this fraction of the average are dead, and left uncorrected
+const MIN_FLAT: f64 = 0.05;
+// frames waiting to be calibrated, beyond which new ones are dropped
+const QUEUE_LENGTH: usize = 2;
+const MASTER_TEMPLATE: &str = "master_{type}_{exposure}_{gain}_{temp}_{date}_{time}";
+
+// each camera has its own defects, so each gets its own file
+fn defects_path(camera: &str) -> Result<PathBuf> {
+    let name = camera
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    let mut path = library::directory()?;
+    path.push(format!("defects_{}.txt", name));
+    Ok(path)
+}
+
+fn load_defects(camera: &str) -> Result<Option<DefectMap>> {
+    let path = defects_path(camera)?;
+    if !path.exists() {
+        return Ok(None);
+    }
+    let defects = DefectMap::parse(&read_to_string(path)?)?;
+    if defects.camera != camera {
+        return Err(format!("Defect map is for {}, not {}", defects.camera, camera).into());
+    }
+    Ok(Some(defects))
+}
+
+#[derive(Clone, Debug)]
+pub struct Master {
+    pub image: Arc<ROIImage>,
+    pub path: PathBuf,
+}
+
+// a master, offset so that it lines up with a frame's ROI
+struct Crop<'a> {
+    master: &'a CpuTexture<u16>,
+    offset: (usize, usize),
+}
+
+impl<'a> Crop<'a> {
+    fn get(&self, x: usize, y: usize) -> f64 {
+        f64::from(self.master[(x + self.offset.0, y + self.offset.1)])
+    }
+}
+
+impl Master {
+    pub fn load(path: &Path) -> Result<Self> {
+        Ok(Self {
+            image: Arc::new(crate::read_image(path)?),
+            path: path.to_path_buf(),
+        })
+    }
+
+    fn mean(&self) -> f64 {
+        let data = self.image.image.data();
+        data.iter().map(|&value| f64::from(value)).sum::<f64>() / data.len() as f64
+    }
+
+    // masters are taken full frame, frames may be a sub-frame of the sensor
+    fn crop(&self, image: &ROIImage, name: &str) -> Result<Crop> {
+        if self.image.meta.bin != image.meta.bin {
+            return Err(format!(
+                "{} master is bin {}, frame is bin {}",
+                name, self.image.meta.bin, image.meta.bin
+            )
+            .into());
+        }
+        let master = &self.image.location;
+        let frame = &image.location;
+        if frame.x < master.x
+            || frame.y < master.y
+            || frame.right() > master.right()
+            || frame.bottom() > master.bottom()
+        {
+            return Err(format!("{} master doesn't cover the frame", name).into());
+        }
+        Ok(Crop {
+            master: &self.image.image,
+            offset: (frame.x - master.x, frame.y - master.y),
+        })
+    }
+}
+
+// everything needed to calibrate one frame, so the pixels can be done off the UI thread
+struct CalibrationJob {
+    image: Arc<ROIImage>,
+    bias: Option<Master>,
+    dark: Option<Master>,
+    flat: Option<Master>,
+    flat_level: f64,
+    dark_scale: f64,
+    defects: Option<Arc<DefectMap>>,
+}
+
+impl CalibrationJob {
+    fn run(self) -> CalibrationResult {
+        let (calibrated, outcome) =
+            if self.bias.is_none() && self.dark.is_none() && self.flat.is_none() {
+                (self.image.clone(), None)
+            } else {
+                match self.calibrate() {
+                    Ok(calibrated) => (Arc::new(calibrated), Some(Ok(()))),
+                    Err(err) => (self.image.clone(), Some(Err(err.to_string()))),
+                }
+            };
+        let calibrated = match self.defects {
+            Some(ref defects) => Arc::new(defects.apply(&calibrated)),
+            None => calibrated,
+        };
+        CalibrationResult {
+            raw: self.image,
+            calibrated,
+            outcome,
+        }
+    }
+
+    fn calibrate(&self) -> Result<ROIImage> {
+        let image = &*self.image;
+        let bias = self
+            .bias
+            .as_ref()
+            .map(|m| m.crop(image, "bias"))
+            .transpose()?;
+        let dark = self
+            .dark
+            .as_ref()
+            .map(|m| m.crop(image, "dark"))
+            .transpose()?;
+        let flat = self
+            .flat
+            .as_ref()
+            .map(|m| m.crop(image, "flat"))
+            .transpose()?;
+        let dark_scale = self.dark_scale;
+        if flat.is_some() && self.flat_level <= 0.0 {
+            return Err("Flat master is no brighter than the bias".into());
+        }
+        // a flat alone only divides, so there's no noise around zero to keep from clipping
+        let pedestal = if bias.is_some() || dark.is_some() {
+            PEDESTAL
+        } else {
+            0.0
+        };
+        let size = image.image.size;
+        let mut data = Vec::with_capacity(size.0 * size.1);
+        for y in 0..size.1 {
+            for x in 0..size.0 {
+                let bias = bias.as_ref().map_or(0.0, |bias| bias.get(x, y));
+                let mut value = f64::from(image.image[(x, y)]) - bias;
+                if let Some(ref dark) = dark {
+                    value -= (dark.get(x, y) - bias) * dark_scale;
+                }
+                if let Some(ref flat) = flat {
+                    let gain = (flat.get(x, y) - bias) / self.flat_level;
+                    if gain > MIN_FLAT {
+                        value /= gain;
+                    }
+                }
+                let value = value + pedestal;
+                data.push(value.max(0.0).min(f64::from(u16::max_value())) as u16);
+            }
+        }
+        Ok(ROIImage {
+            image: CpuTexture::new(data, size),
+            location: image.location.clone(),
+            original: image.original.clone(),
+            meta: image.meta.clone(),
+        })
+    }
+}
+
+/// A frame as it came from the camera, and after calibration and defect correction.
+#[derive(Debug)]
+pub struct CalibrationResult {
+    pub raw: Arc<ROIImage>,
+    pub calibrated: Arc<ROIImage>,
+    // None if there were no masters to apply
+    outcome: Option<std::result::Result<(), String>>,
+}
+
+/// Bias, dark and flat correction of incoming frames, on a background thread.
+pub struct Calibration {
+    pub enabled: bool,
+    // save the calibrated frame rather than the raw one
+    pub save_calibrated: bool,
+    // pick the bias and dark for each frame from the library
+    pub auto: bool,
+    library: Library,
+    bias: Option<Master>,
+    dark: Option<Master>,
+    flat: Option<Master>,
+    // average of the bias subtracted flat, which the flat is normalized by
+    flat_level: f64,
+    // how much the dark current was scaled by for the last frame
+    dark_scale: f64,
+    // the library has darks for the frame, but none that apply unscaled and no bias to scale with
+    dark_unmatched: bool,
+    // shared with the frames queued for calibration
+    defects: Option<Arc<DefectMap>>,
+    // the camera the defect map was last loaded for
+    defects_camera: Option<String>,
+    pub correct_defects: bool,
+    // find defects in the next frame that arrives, on a background thread
+    rebuild_defects: bool,
+    finding_defects: bool,
+    error: Option<String>,
+    send: mpsc::SyncSender<CalibrationJob>,
+    // frames that found the queue full, and were never shown or saved
+    dropped: u64,
+    send_user_update: SendUserUpdate,
+    id: CameraId,
+}
+
+impl Calibration {
+    pub fn new(send_user_update: SendUserUpdate, id: CameraId) -> Self {
+        let (send, recv) = mpsc::sync_channel::<CalibrationJob>(QUEUE_LENGTH);
+        let thread_send_user_update = send_user_update.clone();
+        spawn(move || {
+            while let Ok(job) = recv.recv() {
+                let result = UserUpdate::Calibrated(id, job.run());
+                if thread_send_user_update.send_event(result).is_err() {
+                    break;
+                }
+            }
+        });
+        let (library, error) = match Library::load() {
+            Ok(library) => (library, None),
+            Err(err) => (
+                Library::default(),
+                Some(format!("Unable to load library: {}", err)),
+            ),
+        };
+        Self {
+            enabled: true,
+            save_calibrated: false,
+            auto: true,
+            library,
+            bias: None,
+            dark: None,
+            flat: None,
+            flat_level: 1.0,
+            dark_scale: 1.0,
+            dark_unmatched: false,
+            defects: None,
+            defects_camera: None,
+            correct_defects: true,
+            rebuild_defects: false,
+            finding_defects: false,
+            error,
+            send,
+            dropped: 0,
+            send_user_update,
+            id,
+        }
+    }
+
+    fn slot(&mut self, image_type: ImageType) -> Result<&mut Option<Master>> {
+        match image_type {
+            ImageType::Bias => Ok(&mut self.bias),
+            ImageType::Dark => Ok(&mut self.dark),
+            ImageType::Flat => Ok(&mut self.flat),
+            ImageType::Light => Err("Light frames can't be used as a master".into()),
+        }
+    }
+
+    fn replace(&mut self, image_type: ImageType, master: Option<Master>) -> Result<()> {
+        *self.slot(image_type)? = master;
+        let bias_level = self.bias.as_ref().map_or(0.0, Master::mean);
+        self.flat_level = self
+            .flat
+            .as_ref()
+            .map_or(1.0, |flat| flat.mean() - bias_level);
+        self.error = None;
+        Ok(())
+    }
+
+    /// Picking a bias or dark by hand turns off picking them from the library.
+    pub fn set_master(&mut self, image_type: ImageType, master: Option<Master>) -> Result<()> {
+        if image_type == ImageType::Bias || image_type == ImageType::Dark {
+            self.auto = false;
+        }
+        self.replace(image_type, master)
+    }
+
+    /// Adds a newly built master to the library, and uses it from now on.
+    pub fn register(&mut self, image_type: ImageType, master: Master) -> Result<()> {
+        let entry = Entry::new(image_type, &master);
+        self.replace(image_type, Some(master))?;
+        // reload first, rather than overwriting an index that failed to load
+        let mut library = Library::load()?;
+        library.add(entry)?;
+        self.library = library;
+        Ok(())
+    }
+
+    fn match_masters(&mut self, image: &ROIImage) -> Result<()> {
+        self.dark_unmatched = false;
+        for &image_type in &[ImageType::Bias, ImageType::Dark] {
+            // without a bias the dark can't be scaled, so it has to be taken at the same settings
+            let entry = if image_type == ImageType::Dark && self.bias.is_none() {
+                let entry = self.library.exact_dark(image);
+                self.dark_unmatched =
+                    entry.is_none() && self.library.best_match(image_type, image).is_some();
+                entry
+            } else {
+                self.library.best_match(image_type, image)
+            };
+            let path = entry.map(|entry| entry.path.clone());
+            let current = self.master(image_type).map(|master| &master.path);
+            if path.as_ref() == current {
+                continue;
+            }
+            let master = match path {
+                Some(path) => Some(Master::load(&path)?),
+                None => None,
+            };
+            self.replace(image_type, master)?;
+        }
+        Ok(())
+    }
+
+    pub fn master(&self, image_type: ImageType) -> Option<&Master> {
+        match image_type {
+            ImageType::Bias => self.bias.as_ref(),
+            ImageType::Dark => self.dark.as_ref(),
+            ImageType::Flat => self.flat.as_ref(),
+            ImageType::Light => None,
+        }
+    }
+
+    // the dark current scales with exposure and temperature, but only once the bias is
+    // separated out of it
+    fn dark_scale(&self, image: &ROIImage) -> f64 {
+        let dark = match (&self.bias, &self.dark) {
+            (Some(_), Some(dark)) => &dark.image.meta,
+            _ => return 1.0,
+        };
+        let exposure = match (image.meta.exposure, dark.exposure) {
+            (Some(exposure), Some(dark_exposure)) if dark_exposure.as_secs_f64() > 0.0 => {
+                exposure.as_secs_f64() / dark_exposure.as_secs_f64()
+            }
+            _ => 1.0,
+        };
+        let temperature = match (image.meta.temperature, dark.temperature) {
+            (Some(temperature), Some(dark_temperature)) => {
+                2f64.powf((temperature - dark_temperature) / DARK_DOUBLING_TEMPERATURE)
+            }
+            _ => 1.0,
+        };
+        exposure * temperature
+    }
+
+    fn set_defects(&mut self, defects: DefectMap) -> Result<()> {
+        let path = defects_path(&defects.camera)?;
+        if let Some(directory) = path.parent() {
+            create_dir_all(directory)?;
+        }
+        write(path, defects.format())?;
+        self.defects_camera = Some(defects.camera.clone());
+        self.defects = Some(Arc::new(defects));
+        Ok(())
+    }
+
+    pub fn defects_from_dark(&mut self) -> Result<()> {
+        let dark = self
+            .dark
+            .as_ref()
+            .ok_or("No dark master to find defects in")?;
+        let defects = DefectMap::from_dark(&dark.image);
+        self.set_defects(defects)
+    }
+
+    pub fn defects_from_next_frame(&mut self) {
+        self.rebuild_defects = true;
+    }
+
+    /// Takes the result of defects_from_next_frame.
+    pub fn defects_found(&mut self, defects: DefectMap) {
+        self.finding_defects = false;
+        if let Err(err) = self.set_defects(defects) {
+            self.error = Some(format!("Unable to save defect map: {}", err));
+        }
+    }
+
+    pub fn clear_defects(&mut self) -> Result<()> {
+        if let Some(ref camera) = self.defects_camera {
+            let path = defects_path(camera)?;
+            if path.exists() {
+                remove_file(path)?;
+            }
+        }
+        self.defects = None;
+        Ok(())
+    }
+
+    /// Queues the frame to be calibrated and have its defects replaced, before it's displayed,
+    /// stacked or searched for stars. It comes back as a UserUpdate::Calibrated, unless the
+    /// queue is full and it's dropped.
+    pub fn apply(&mut self, image: Arc<ROIImage>) -> Result<()> {
+        let camera = &image.meta.camera;
+        if self.defects_camera.as_ref() != Some(camera) {
+            self.defects_camera = Some(camera.clone());
+            self.defects = load_defects(camera)
+                .map(|defects| defects.map(Arc::new))
+                .unwrap_or_else(|err| {
+                    self.error = Some(format!("Unable to load defect map: {}", err));
+                    None
+                });
+        }
+        if self.rebuild_defects {
+            self.rebuild_defects = false;
+            self.finding_defects = true;
+            let image = image.clone();
+            let send_user_update = self.send_user_update.clone();
+            let id = self.id;
+            spawn(move || {
+                let defects = DefectMap::from_frame(&image);
+                // the UI may have gone away in the meantime, nothing to do about it
+                let _ = send_user_update.send_event(UserUpdate::DefectsFound(id, defects));
+            });
+        }
+        let apply_masters = self.match_masters_for(&image);
+        let master = |master: &Option<Master>| master.clone().filter(|_| apply_masters);
+        let defects = match self.defects {
+            Some(ref defects)
+                if self.correct_defects
+                    && defects.camera == image.meta.camera
+                    && defects.bin == image.meta.bin =>
+            {
+                Some(defects.clone())
+            }
+            _ => None,
+        };
+        let job = CalibrationJob {
+            bias: master(&self.bias),
+            dark: master(&self.dark),
+            flat: master(&self.flat),
+            flat_level: self.flat_level,
+            dark_scale: self.dark_scale,
+            defects,
+            image,
+        };
+        match self.send.try_send(job) {
+            Ok(()) => Ok(()),
+            Err(mpsc::TrySendError::Full(_)) => {
+                self.dropped += 1;
+                Ok(())
+            }
+            Err(mpsc::TrySendError::Disconnected(_)) => {
+                Err("Calibration thread disconnected".into())
+            }
+        }
+    }
+
+    // false if calibration is off or the masters couldn't be matched, and the frame should only
+    // have its defects corrected
+    fn match_masters_for(&mut self, image: &ROIImage) -> bool {
+        if !self.enabled {
+            return false;
+        }
+        if self.auto {
+            if let Err(err) = self.match_masters(image) {
+                self.error = Some(err.to_string());
+                return false;
+            }
+        }
+        self.dark_scale = self.dark_scale(image);
+        true
+    }
+
+    /// Takes a frame back from the calibration thread, returning the raw and calibrated frames.
+    pub fn user_update(&mut self, result: CalibrationResult) -> (Arc<ROIImage>, Arc<ROIImage>) {
+        match result.outcome {
+            Some(Ok(())) => self.error = None,
+            Some(Err(err)) => self.error = Some(err),
+            None => (),
+        }
+        (result.raw, result.calibrated)
+    }
+
+    pub fn status(&self, status: &mut String) -> Result<()> {
+        writeln!(
+            status,
+            "calibrate: {} (save calibrated: {}) auto: {} ({} masters)",
+            self.enabled,
+            self.save_calibrated,
+            self.auto,
+            self.library.entries().len()
+        )?;
+        for &image_type in &[ImageType::Bias, ImageType::Dark, ImageType::Flat] {
+            if let Some(master) = self.master(image_type) {
+                writeln!(
+                    status,
+                    "{}: {}",
+                    image_type.short_name(),
+                    master.path.display()
+                )?;
+            }
+        }
+        if self.bias.is_some() && self.dark.is_some() {
+            writeln!(status, "dark scale: {:.3}", self.dark_scale)?;
+        }
+        if self.auto && self.dark_unmatched {
+            writeln!(
+                status,
+                "dark: none at this exposure and temperature, and no bias to scale one"
+            )?;
+        }
+        match self.defects {
+            Some(ref defects) => writeln!(
+                status,
+                "defects: {} pixels at bin {} (correct: {})",
+                defects.count(),
+                defects.bin,
+                self.correct_defects
+            )?,
+            None if self.finding_defects => writeln!(status, "defects: finding")?,
+            None if self.rebuild_defects => writeln!(status, "defects: waiting for a frame")?,
+            None => writeln!(status, "defects: none")?,
+        }
+        if self.dropped > 0 {
+            writeln!(
+                status,
+                "calibration error: queue full, {} frames dropped",
+                self.dropped
+            )?;
+        }
+        if let Some(ref error) = self.error {
+            writeln!(status, "calibration error: {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+fn build_master(
+    frames: &[Arc<ROIImage>],
+    rejection: Rejection,
+    observation: &Observation,
+) -> Result<Master> {
+    let image = combine(frames, rejection)?;
+    let directory = library::directory()?;
+    let fields = NameFields {
+        meta: &image.meta,
+        observation,
+        time: time::OffsetDateTime::now_local(),
+        folder: "",
+    };
+    let path = naming::expand(&directory, MASTER_TEMPLATE, &fields)?;
+    let path = naming::free_filename(path, "fits")?;
+    create_dir_all(&directory)?;
+    fits::write_fits(&path, &image, observation)?;
+    Ok(Master {
+        image: Arc::new(image),
+        path,
+    })
+}
+
+/// Collects frames from the camera for a master, then combines and saves them on a
+/// background thread. Every frame is held in memory until then.
+pub struct MasterBuilder {
+    image_type: ImageType,
+    count: usize,
+    rejection: Rejection,
+    frames: Vec<Arc<ROIImage>>,
+}
+
+impl MasterBuilder {
+    pub fn new(image_type: ImageType, count: usize, rejection: Rejection) -> Result<Self> {
+        if image_type == ImageType::Light {
+            return Err("Light frames can't be used as a master".into());
+        }
+        // rejection needs a few frames to estimate the spread from
+        if count < 3 {
+            return Err("A master needs at least 3 frames".into());
+        }
+        Ok(Self {
+            image_type,
+            count,
+            rejection,
+            frames: Vec::with_capacity(count),
+        })
+    }
+
+    // true once enough frames have been collected
+    pub fn add(&mut self, frame: Arc<ROIImage>) -> bool {
+        self.frames.push(frame);
+        self.frames.len() >= self.count
+    }
+
+    pub fn build(self, observation: &Observation, send_user_update: SendUserUpdate, id: CameraId) {
+        let observation = Observation {
+            object: String::new(),
+            image_type: self.image_type,
+            ..observation.clone()
+        };
+        spawn(move || {
+            let result = build_master(&self.frames, self.rejection, &observation)
+                .map_err(|err| err.to_string());
+            let update = UserUpdate::MasterFinished(id, self.image_type, result);
+            // nothing to tell if the UI is already gone
+            let _ = send_user_update.send_event(update);
+        });
+    }
+
+    pub fn status(&self, status: &mut String) -> Result<()> {
+        writeln!(
+            status,
+            "building master {}: {}/{} frames ({})",
+            self.image_type.short_name(),
+            self.frames.len(),
+            self.count,
+            self.rejection.name()
+        )?;
+        Ok(())
+    }
+}
@@ -0,0 +1,189 @@
+use crate::{
+    camera::{interface::CameraDriver, qhycamera::ControlId},
+    Result,
+};
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+const STEP_INTERVAL: Duration = Duration::from_secs(1);
+// how far back the temperature/PWM trend looks
+const HISTORY_LENGTH: Duration = Duration::from_secs(60);
+// degrees C from the setpoint that still counts as having reached it
+const SETPOINT_TOLERANCE: f64 = 0.5;
+// warming up ramps towards this before turning the cooler off
+const WARM_TARGET: f64 = 15.0;
+// degrees C per minute
+pub const DEFAULT_RATE: f64 = 2.0;
+
+#[derive(Clone, Debug, Default)]
+pub struct CoolerStatus {
+    pub temperature: Option<f64>,
+    // 0-255, as reported by ControlCurpwm
+    pub pwm: Option<f64>,
+    pub target: Option<f64>,
+    pub setpoint: Option<f64>,
+    // per minute
+    pub temperature_trend: f64,
+    pub pwm_trend: f64,
+    pub warming: bool,
+    pub at_setpoint: bool,
+}
+
+/// Ramps the cooler setpoint towards a target temperature, rather than jumping straight to it.
+pub struct Cooler {
+    target: Option<f64>,
+    rate: f64,
+    setpoint: Option<f64>,
+    warming: bool,
+    last_step: Option<Instant>,
+    // (time, temperature, pwm)
+    history: VecDeque<(Instant, f64, f64)>,
+    status: CoolerStatus,
+}
+
+fn has_cooler(camera: &dyn CameraDriver) -> bool {
+    camera
+        .control_values()
+        .iter()
+        .any(|c| c.id == ControlId::ControlCooler)
+}
+
+fn check_rate(rate: f64) -> Result<()> {
+    if rate > 0.0 && rate.is_finite() {
+        Ok(())
+    } else {
+        Err(format!("Cooling rate must be positive, not {}", rate).into())
+    }
+}
+
+impl Cooler {
+    pub fn new() -> Self {
+        Self {
+            target: None,
+            rate: DEFAULT_RATE,
+            setpoint: None,
+            warming: false,
+            last_step: None,
+            history: VecDeque::new(),
+            status: CoolerStatus::default(),
+        }
+    }
+
+    pub fn cool(&mut self, camera: &dyn CameraDriver, target: f64, rate: f64) -> Result<()> {
+        if !has_cooler(camera) {
+            return Err("Camera has no cooler".into());
+        }
+        check_rate(rate)?;
+        self.target = Some(target);
+        self.rate = rate;
+        self.warming = false;
+        Ok(())
+    }
+
+    pub fn warm(&mut self, camera: &mut dyn CameraDriver, rate: f64) -> Result<()> {
+        if !has_cooler(camera) {
+            return Err("Camera has no cooler".into());
+        }
+        check_rate(rate)?;
+        let temperature = camera
+            .control_values()
+            .iter()
+            .find(|c| c.id == ControlId::ControlCurtemp)
+            .map(|c| c.value);
+        // nothing to ramp if the sensor is already warm
+        if temperature.map_or(false, |temperature| temperature >= WARM_TARGET) {
+            return self.turn_off(camera);
+        }
+        self.target = Some(WARM_TARGET);
+        self.rate = rate;
+        self.warming = true;
+        Ok(())
+    }
+
+    fn turn_off(&mut self, camera: &mut dyn CameraDriver) -> Result<()> {
+        camera.set_control(ControlId::ControlManulpwm, 0.0)?;
+        self.target = None;
+        self.setpoint = None;
+        self.warming = false;
+        Ok(())
+    }
+
+    pub fn status(&self) -> CoolerStatus {
+        self.status.clone()
+    }
+
+    /// Moves the setpoint on by the time since the last step. Cheap to call often, it only does
+    /// anything once per STEP_INTERVAL.
+    pub fn step(&mut self, camera: &mut dyn CameraDriver) -> Result<()> {
+        let now = Instant::now();
+        let dt = match self.last_step {
+            Some(last_step) if now - last_step < STEP_INTERVAL => return Ok(()),
+            Some(last_step) => now - last_step,
+            None => Duration::from_secs(0),
+        };
+        self.last_step = Some(now);
+
+        let controls = camera.control_values();
+        let get = |id: ControlId| controls.iter().find(|c| c.id == id).map(|c| c.value);
+        let temperature = get(ControlId::ControlCurtemp);
+        let pwm = get(ControlId::ControlCurpwm);
+        if let (Some(temperature), Some(pwm)) = (temperature, pwm) {
+            self.history.push_back((now, temperature, pwm));
+        }
+        while let Some(&(time, _, _)) = self.history.front() {
+            if now - time > HISTORY_LENGTH {
+                self.history.pop_front();
+            } else {
+                break;
+            }
+        }
+
+        let mut reached = false;
+        if let Some(target) = self.target {
+            let setpoint = match self.setpoint {
+                Some(setpoint) => {
+                    let max_step = self.rate * dt.as_secs_f64() / 60.0;
+                    if (target - setpoint).abs() <= max_step {
+                        reached = true;
+                        target
+                    } else {
+                        setpoint + max_step * (target - setpoint).signum()
+                    }
+                }
+                // start the ramp from wherever the sensor is right now
+                None => temperature.unwrap_or(target),
+            };
+            camera.set_control(ControlId::ControlCooler, setpoint)?;
+            self.setpoint = Some(setpoint);
+            if reached && self.warming {
+                self.turn_off(camera)?;
+                reached = false;
+            }
+        }
+
+        let (temperature_trend, pwm_trend) = match (self.history.front(), self.history.back()) {
+            (Some(first), Some(last)) if last.0 - first.0 > Duration::from_secs(5) => {
+                let minutes = (last.0 - first.0).as_secs_f64() / 60.0;
+                ((last.1 - first.1) / minutes, (last.2 - first.2) / minutes)
+            }
+            _ => (0.0, 0.0),
+        };
+        let at_setpoint = reached
+            && temperature.map_or(false, |temperature| {
+                (temperature - self.target.unwrap_or(temperature)).abs() < SETPOINT_TOLERANCE
+            });
+        self.status = CoolerStatus {
+            temperature,
+            pwm,
+            target: self.target,
+            setpoint: self.setpoint,
+            temperature_trend,
+            pwm_trend,
+            warming: self.warming,
+            at_setpoint,
+        };
+        Ok(())
+    }
+}
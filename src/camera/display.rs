@@ -1,53 +1,136 @@
 use crate::{
-    alg::process,
+    alg::{
+        colormap::Colormap, combine::Rejection, livestack::LiveStack, lucky::Lucky, process,
+        StackedImage,
+    },
+    calibration::{Calibration, Master, MasterBuilder},
     camera,
-    camera::qhycamera::{ControlId, EXPOSURE_FACTOR},
-    image_display::ImageDisplay,
-    mount,
+    camera::{
+        cooler,
+        interface::{CameraRole, FrameMetadata, ROIImage},
+        qhycamera::{ControlId, EXPOSURE_FACTOR},
+        recorder::RecordLimit,
+        simulated::Psf,
+        CameraId,
+    },
+    config,
+    fits::{self, ImageType, Observation},
+    image_display::{draw_histogram, ImageDisplay},
+    mount, naming,
+    naming::NameFields,
     platesolve::platesolve,
+    writer::{SaveFormat, SaveJob, Writer},
     Key, Result, SendUserUpdate, UserUpdate,
 };
-use khygl::{render_texture::TextureRenderer, texture::CpuTexture, Rect};
+use khygl::{render_text::TextRenderer, render_texture::TextureRenderer, Rect};
 use std::{
-    collections::HashMap, convert::TryInto, fmt::Write, fs::create_dir_all, path::PathBuf,
-    time::Instant,
+    collections::HashMap,
+    convert::TryInto,
+    fmt::Write,
+    fs::create_dir_all,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
-// TODO: make saving images async
+const HISTOGRAM_SIZE: (usize, usize) = (512, 128);
+
+fn default_save_root() -> PathBuf {
+    dirs::desktop_dir().unwrap_or_else(PathBuf::new)
+}
+
 pub struct CameraDisplay {
+    id: CameraId,
+    role: CameraRole,
     camera: Option<camera::thread::CameraAsync>,
     send_user_update: SendUserUpdate,
     image_display: ImageDisplay,
     processor: process::Processor,
+    writer: Writer,
+    calibration: Calibration,
+    master: Option<MasterBuilder>,
+    // when stacking, the stack is displayed instead of each frame
+    livestack: Option<LiveStack>,
+    livestack_stack: Option<StackedImage>,
+    livestack_saved: Option<PathBuf>,
+    // a finished lucky run keeps its stack displayed until stopped
+    lucky: Option<Lucky>,
+    lucky_stack: Option<StackedImage>,
+    lucky_saved: Option<PathBuf>,
+    // outcome of the last master that was built
+    master_status: String,
     roi_thing: ROIThing,
     display_interesting: bool,
     save: usize,
     folder: String,
+    save_root: PathBuf,
+    template: String,
+    format: SaveFormat,
+    observation: Observation,
     solve_status: String,
     cached_status: String,
 }
 
 impl CameraDisplay {
-    pub fn new(send_user_update: SendUserUpdate) -> Self {
-        if let Ok(img) = crate::read_png("telescope.2019-11-21.19-39-54.png") {
-            send_user_update
-                .send_event(UserUpdate::CameraData(std::sync::Arc::new(img.into())))
-                .unwrap();
-        }
+    pub fn new(send_user_update: SendUserUpdate, id: CameraId, role: CameraRole) -> Self {
         Self {
-            camera: Some(camera::thread::CameraAsync::new(send_user_update.clone())),
+            id,
+            role,
+            camera: Some(camera::thread::CameraAsync::new(
+                send_user_update.clone(),
+                id,
+                role,
+                move || camera::thread::connect(role),
+            )),
             send_user_update: send_user_update.clone(),
             image_display: ImageDisplay::new(),
-            processor: process::Processor::new(send_user_update),
+            processor: process::Processor::new(send_user_update.clone(), id),
+            writer: Writer::new(send_user_update.clone(), id),
+            calibration: Calibration::new(send_user_update, id),
+            master: None,
+            livestack: None,
+            livestack_stack: None,
+            livestack_saved: None,
+            lucky: None,
+            lucky_stack: None,
+            lucky_saved: None,
+            master_status: String::new(),
             roi_thing: ROIThing::new(),
             display_interesting: true,
             save: 0,
             folder: String::new(),
+            save_root: config::get("save.root")
+                .filter(|root| !root.is_empty())
+                .map(PathBuf::from)
+                .unwrap_or_else(default_save_root),
+            // a template saved by an older version may no longer be valid
+            template: config::get("save.template")
+                .filter(|template| !template.is_empty() && naming::validate(template).is_ok())
+                .unwrap_or_else(|| naming::DEFAULT_TEMPLATE.to_string()),
+            format: SaveFormat::Png,
+            observation: Observation {
+                object: String::new(),
+                telescope: config::get("telescope").unwrap_or_default(),
+                filter: String::new(),
+                image_type: ImageType::Light,
+            },
             solve_status: String::new(),
             cached_status: String::new(),
         }
     }
 
+    pub fn id(&self) -> CameraId {
+        self.id
+    }
+
+    // one line description, for the list of all cameras
+    pub fn summary(&self) -> String {
+        match self.camera {
+            Some(ref camera) => format!("{} {} {}", self.id, self.role, camera.data.name),
+            None => format!("{} {} (disconnected)", self.id, self.role),
+        }
+    }
+
     pub fn cmd(&mut self, command: &[&str]) -> Result<bool> {
         if self.processor.cmd(command)? {
             return Ok(true);
@@ -56,15 +139,64 @@ impl CameraDisplay {
             ["cross"] => {
                 self.image_display.cross = !self.image_display.cross;
             }
+            ["stars"] => {
+                self.image_display.stars = !self.image_display.stars;
+            }
+            ["colormap", name] => match Colormap::parse(name) {
+                Some(colormap) => self.image_display.set_colormap(colormap),
+                None => return Err(format!("Unknown colormap: {}", name).into()),
+            },
             ["bin"] => {
                 self.image_display.bin = !self.image_display.bin;
             }
+            ["bin", bin] => match bin.parse::<usize>() {
+                Ok(bin) if (1..=4).contains(&bin) => self.camera_op(|c| c.set_bin(bin)),
+                _ => return Ok(false),
+            },
+            ["bits", "8"] => {
+                self.camera_op(|c| c.set_bits(8));
+            }
+            ["bits", "16"] => {
+                self.camera_op(|c| c.set_bits(16));
+            }
             ["interesting"] => {
                 self.display_interesting = !self.display_interesting;
             }
             ["live"] => {
                 self.camera_op(|c| c.toggle_live());
             }
+            ["cool", target] => {
+                let target = target.parse::<f64>()?;
+                self.camera_op(|c| c.cool(target, cooler::DEFAULT_RATE));
+            }
+            ["cool", target, rate] => {
+                let target = target.parse::<f64>()?;
+                let rate = rate.parse::<f64>()?;
+                self.camera_op(|c| c.cool(target, rate));
+            }
+            ["warm"] => {
+                self.camera_op(|c| c.warm(cooler::DEFAULT_RATE));
+            }
+            ["warm", rate] => {
+                let rate = rate.parse::<f64>()?;
+                self.camera_op(|c| c.warm(rate));
+            }
+            ["cameras"] => {
+                self.camera_op(|c| c.list());
+            }
+            ["camera", "select", selector] => {
+                let selector = selector.to_string();
+                self.camera_op(move |c| c.select(selector));
+            }
+            ["simulate"] => {
+                self.camera_op(|c| c.simulate(Psf::default()));
+            }
+            ["simulate", "gaussian"] => {
+                self.camera_op(|c| c.simulate(Psf::Gaussian));
+            }
+            ["simulate", "moffat"] => {
+                self.camera_op(|c| c.simulate(Psf::default()));
+            }
             ["open"] if self.camera.as_ref().map_or(false, |c| !c.data.running) => {
                 self.camera_op(|c| c.start());
             }
@@ -77,12 +209,56 @@ impl CameraDisplay {
             ["folder", name] => {
                 self.folder = name.to_string();
             }
+            ["saveroot"] => {
+                self.save_root = default_save_root();
+                config::set("save.root", "")?;
+            }
+            ["saveroot", path] => {
+                self.save_root = PathBuf::from(path);
+                config::set("save.root", path)?;
+            }
+            ["template"] => {
+                self.template = naming::DEFAULT_TEMPLATE.to_string();
+                config::set("save.template", "")?;
+            }
+            ["template", template] => {
+                naming::validate(template)?;
+                self.template = template.to_string();
+                config::set("save.template", template)?;
+            }
+            ["format", "png"] => {
+                self.format = SaveFormat::Png;
+            }
+            ["format", "fits"] => {
+                self.format = SaveFormat::Fits;
+            }
+            ["object"] => {
+                self.observation.object = String::new();
+            }
+            ["object", name] => {
+                self.observation.object = name.to_string();
+            }
+            ["filter"] => {
+                self.observation.filter = String::new();
+            }
+            ["filter", name] => {
+                self.observation.filter = name.to_string();
+            }
+            ["telescope", name] => {
+                self.observation.telescope = name.to_string();
+                config::set("telescope", name)?;
+            }
+            ["frametype", image_type] => {
+                self.observation.image_type = image_type.parse()?;
+            }
             ["save"] => {
                 self.save += 1;
             }
             ["save", "now"] if self.image_display.raw().is_some() => {
-                if let Some(ref raw) = self.image_display.raw() {
-                    self.save_png(&raw.image)?;
+                if let Some(raw) = self.image_display.raw().clone() {
+                    if !self.save_image(raw)? {
+                        return Err("Save queue is full".into());
+                    }
                 }
             }
             ["save", n] => {
@@ -92,9 +268,128 @@ impl CameraDisplay {
                     return Ok(false);
                 }
             }
+            ["record", "stop"] => {
+                self.camera_op(|c| c.stop_recording());
+            }
+            ["record", limit] => {
+                let length = if limit.ends_with('s') {
+                    let seconds = limit[..limit.len() - 1].parse::<f64>()?;
+                    if seconds <= 0.0 || !seconds.is_finite() {
+                        return Err(format!("Invalid recording length: {}", limit).into());
+                    }
+                    RecordLimit::Time(Duration::from_secs_f64(seconds))
+                } else {
+                    RecordLimit::Frames(limit.parse::<u64>()?)
+                };
+                // a recording that ends before its first frame would only leave an empty file
+                let empty = match length {
+                    RecordLimit::Frames(frames) => frames == 0,
+                    RecordLimit::Time(duration) => duration == Duration::from_secs(0),
+                };
+                if empty {
+                    return Err(format!("Invalid recording length: {}", limit).into());
+                }
+                let path = self.output_path("ser")?;
+                self.camera_op(move |c| c.record(path, length));
+            }
+            ["load", path] => {
+                self.show_image(Arc::new(crate::read_image(path)?))?;
+            }
+            ["calibrate"] => {
+                self.calibration.enabled = !self.calibration.enabled;
+            }
+            ["calibrate", "save"] => {
+                self.calibration.save_calibrated = !self.calibration.save_calibrated;
+            }
+            ["defects"] => {
+                self.calibration.correct_defects = !self.calibration.correct_defects;
+            }
+            ["defects", "dark"] => self.calibration.defects_from_dark()?,
+            ["defects", "frame"] => self.calibration.defects_from_next_frame(),
+            ["defects", "clear"] => self.calibration.clear_defects()?,
+            ["calibrate", "auto"] => {
+                self.calibration.auto = !self.calibration.auto;
+            }
+            ["calibrate", image_type] => {
+                self.calibration.set_master(image_type.parse()?, None)?;
+            }
+            ["livestack", "start"] => {
+                if self.livestack.is_none() {
+                    self.livestack = Some(LiveStack::new(self.send_user_update.clone(), self.id));
+                    self.livestack_stack = None;
+                    self.livestack_saved = None;
+                }
+            }
+            ["livestack", "stop"] => {
+                self.livestack = None;
+            }
+            ["livestack", "reset"] => match self.livestack {
+                Some(ref mut livestack) => {
+                    livestack.reset()?;
+                    self.livestack_stack = None;
+                    self.livestack_saved = None;
+                }
+                None => return Err("Live stacking isn't running".into()),
+            },
+            ["livestack", "save"] => {
+                let stack = self
+                    .livestack_stack
+                    .as_ref()
+                    .ok_or("No live stack to save")?;
+                self.livestack_saved = Some(self.save_stack(stack)?);
+            }
+            ["lucky", "stop"] => {
+                self.lucky = None;
+                self.lucky_stack = None;
+                self.lucky_saved = None;
+            }
+            ["lucky", "save"] => {
+                let stack = self.lucky_stack.as_ref().ok_or("No lucky stack to save")?;
+                self.lucky_saved = Some(self.save_stack(stack)?);
+            }
+            ["lucky", frames, percent] => {
+                let frames = frames.parse::<usize>()?;
+                let percent = percent.parse::<f64>()?;
+                if frames == 0 || percent <= 0.0 || percent > 100.0 {
+                    return Err("lucky needs a frame count and a percentage to keep".into());
+                }
+                self.lucky = Some(Lucky::new(
+                    self.send_user_update.clone(),
+                    self.id,
+                    frames,
+                    percent / 100.0,
+                ));
+                // a save must not pick up the previous run's stack
+                self.lucky_stack = None;
+                self.lucky_saved = None;
+            }
+            ["master", "stop"] => {
+                self.master = None;
+            }
+            ["master", image_type, count] => {
+                self.master = Some(MasterBuilder::new(
+                    image_type.parse()?,
+                    count.parse()?,
+                    Rejection::SigmaClip,
+                )?);
+            }
+            ["master", image_type, count, rejection] => {
+                let rejection = Rejection::parse(rejection)
+                    .ok_or_else(|| format!("Unknown rejection: {}", rejection))?;
+                self.master = Some(MasterBuilder::new(
+                    image_type.parse()?,
+                    count.parse()?,
+                    rejection,
+                )?);
+            }
+            ["calibrate", image_type, path] => {
+                let master = Master::load(Path::new(path))?;
+                self.calibration
+                    .set_master(image_type.parse()?, Some(master))?;
+            }
             ["solve"] => {
                 if let Some(ref raw) = self.image_display.raw() {
-                    platesolve(&raw.image, self.send_user_update.clone())?;
+                    platesolve(&raw.image, self.id, self.send_user_update.clone())?;
                 } else {
                     return Ok(false);
                 }
@@ -141,6 +436,10 @@ impl CameraDisplay {
         self.roi_thing.update()
     }
 
+    pub fn mount_update(&mut self, data: mount::thread::MountData) {
+        self.camera_op(move |c| c.set_mount_data(data));
+    }
+
     pub fn status(&mut self, status: &mut String, infrequent_update: bool) -> Result<()> {
         if infrequent_update {
             self.cached_status.clear();
@@ -172,7 +471,10 @@ impl CameraDisplay {
             }
         }
         if let Some(ref camera) = self.camera {
-            writeln!(status, "{}", camera.data.name)?;
+            writeln!(status, "{} ({})", camera.data.name, camera.data.role)?;
+            for (index, name) in camera.data.available.iter().enumerate() {
+                writeln!(status, "  camera select {}: {}", index, name)?;
+            }
             if camera.data.running {
                 writeln!(status, "close: (running)")?;
             } else {
@@ -183,18 +485,87 @@ impl CameraDisplay {
             } else {
                 writeln!(status, "live: (not live)")?;
             }
+            writeln!(
+                status,
+                "bin [n]: {}x{} bits [8|16]: {}",
+                camera.data.bin, camera.data.bin, camera.data.bits
+            )?;
             writeln!(status, "Last exposure: {:?}", camera.data.exposure_duration)?;
+            let cooler = &camera.data.cooler;
+            if let (Some(temperature), Some(pwm)) = (cooler.temperature, cooler.pwm) {
+                writeln!(
+                    status,
+                    "cool [target] [rate]|warm: {:.1}C ({:+.1}C/min) pwm {:.0}% ({:+.1}%/min)",
+                    temperature,
+                    cooler.temperature_trend,
+                    pwm / 2.55,
+                    cooler.pwm_trend / 2.55,
+                )?;
+                if let (Some(target), Some(setpoint)) = (cooler.target, cooler.setpoint) {
+                    let state = if cooler.at_setpoint {
+                        "at setpoint"
+                    } else if cooler.warming {
+                        "warming"
+                    } else {
+                        "ramping"
+                    };
+                    writeln!(
+                        status,
+                        "  target {:.1}C setpoint {:.1}C ({})",
+                        target, setpoint, state
+                    )?;
+                }
+            }
+            if let Some(ref recording) = camera.data.recording {
+                let (fps, mbps) = recording.throughput();
+                let state = if recording.finished {
+                    "finished"
+                } else {
+                    "recording"
+                };
+                writeln!(
+                    status,
+                    "record [n]s|[frames]|stop: {} {} frames, {} dropped ({:.1} fps, {:.1} MB/s)",
+                    state, recording.written, recording.dropped, fps, mbps
+                )?;
+                if let Some(ref path) = recording.path {
+                    writeln!(status, "  {}", path.display())?;
+                }
+                if let Some(ref error) = recording.error {
+                    writeln!(status, "  record error: {}", error)?;
+                }
+            } else {
+                writeln!(status, "record [n]s|[frames]: (not recording)")?;
+            }
             if !camera.data.cmd_status.is_empty() {
                 writeln!(status, "Camera error: {}", camera.data.cmd_status)?;
             }
         }
         writeln!(
             status,
-            "cross:{}|bin:{}",
-            self.image_display.cross, self.image_display.bin,
+            "cross:{}|bin:{}|stars:{}",
+            self.image_display.cross, self.image_display.bin, self.image_display.stars,
+        )?;
+        writeln!(
+            status,
+            "colormap: {} (grey, viridis, heat, cubehelix, clip)",
+            self.image_display.colormap().name()
         )?;
         writeln!(status, "interesting: {}", self.display_interesting)?;
-        writeln!(status, "save|save [n]: {}", self.save)?;
+        writeln!(status, "save|save [n]|load [path]: {}", self.save)?;
+        writeln!(
+            status,
+            "format [png|fits]: {} frametype [light|dark|flat|bias]: {}",
+            self.format.extension(),
+            self.observation.image_type
+        )?;
+        writeln!(
+            status,
+            "object [name]: {} filter [name]: {} telescope [name]: {}",
+            self.observation.object, self.observation.filter, self.observation.telescope
+        )?;
+        writeln!(status, "saveroot [path]: {}", self.save_root.display())?;
+        writeln!(status, "template [template]: {}", self.template)?;
         if !self.solve_status.is_empty() {
             writeln!(status, "solve: {}", self.solve_status)?;
         }
@@ -203,57 +574,128 @@ impl CameraDisplay {
         } else {
             writeln!(status, "folder: {}", self.folder)?;
         }
-        self.processor.status(status)?;
-        write!(status, "{}", self.cached_status)?;
-        Ok(())
-    }
-
-    fn get_filename(&self) -> Result<PathBuf> {
-        let tm = time::OffsetDateTime::now_local();
-        let dirname = tm.format("%Y_%m_%d");
-        let mut filepath = dirs::desktop_dir().unwrap_or_else(PathBuf::new);
-        filepath.push(dirname);
-        if !self.folder.is_empty() {
-            filepath.push(&self.folder);
+        self.writer.status(status)?;
+        self.calibration.status(status)?;
+        if let Some(ref livestack) = self.livestack {
+            livestack.status(status)?;
         }
-        if !filepath.exists() {
-            create_dir_all(&filepath)?;
+        if let Some(ref livestack_saved) = self.livestack_saved {
+            writeln!(status, "livestack saved: {}", livestack_saved.display())?;
         }
-        let filepath1 = filepath.join(tm.format("telescope.%Y-%m-%d.%H-%M-%S.png"));
-        if !filepath1.exists() {
-            return Ok(filepath1);
+        if let Some(ref lucky) = self.lucky {
+            lucky.status(status)?;
         }
-        let filepath2 = filepath.join(tm.format("telescope.%Y-%m-%d.%H-%M-%S.%N.png"));
-        if !filepath2.exists() {
-            return Ok(filepath2);
+        if let Some(ref lucky_saved) = self.lucky_saved {
+            writeln!(status, "lucky saved: {}", lucky_saved.display())?;
         }
-        for i in 1.. {
-            let filepath3 =
-                filepath.join(tm.format(format!("telescope.%Y-%m-%d.%H-%M-%S.%N.{}.png", i)));
-            if !filepath3.exists() {
-                return Ok(filepath3);
-            }
+        if let Some(ref master) = self.master {
+            master.status(status)?;
+        } else if !self.master_status.is_empty() {
+            writeln!(status, "{}", self.master_status)?;
         }
-        panic!("Unable to find free file");
+        self.processor.status(status)?;
+        write!(status, "{}", self.cached_status)?;
+        Ok(())
     }
 
-    fn save_png(&self, data: &CpuTexture<u16>) -> Result<()> {
-        crate::write_png(self.get_filename()?, data)?;
-        Ok(())
+    fn save_image(&mut self, image: Arc<ROIImage>) -> Result<bool> {
+        self.writer.save(SaveJob {
+            image,
+            root: self.save_root.clone(),
+            template: self.template.clone(),
+            folder: self.folder.clone(),
+            time: time::OffsetDateTime::now_local(),
+            format: self.format,
+            observation: self.observation.clone(),
+        })
+    }
+
+    // stacks are saved at full precision, which only fits can hold
+    fn save_stack(&self, stack: &StackedImage) -> Result<PathBuf> {
+        let path = self.output_path("fits")?;
+        fits::write_fits_f32(
+            &path,
+            &stack.pixels,
+            stack.size,
+            &stack.image.meta,
+            &self.observation,
+        )?;
+        Ok(path)
+    }
+
+    fn output_path(&self, extension: &str) -> Result<PathBuf> {
+        let default_meta = FrameMetadata::default();
+        let meta = match self.image_display.raw() {
+            Some(ref raw) => &raw.meta,
+            None => &default_meta,
+        };
+        let fields = NameFields {
+            meta,
+            observation: &self.observation,
+            time: time::OffsetDateTime::now_local(),
+            folder: &self.folder,
+        };
+        let path = naming::expand(&self.save_root, &self.template, &fields)?;
+        let path = naming::free_filename(path, extension)?;
+        if let Some(directory) = path.parent() {
+            create_dir_all(directory)?;
+        }
+        Ok(path)
     }
 
     pub fn draw(
         &mut self,
         pos: Rect<usize>,
         displayer: &TextureRenderer,
+        text_renderer: &mut TextRenderer,
         screen_size: (f32, f32),
     ) -> Result<()> {
         if let Some(scale_offset) = self.processor.get_scale_offset() {
-            self.image_display.scale_offset = (scale_offset.0 as f32, scale_offset.1 as f32);
+            self.image_display
+                .set_scale_offset((scale_offset.0 as f32, scale_offset.1 as f32));
         };
+        self.image_display.set_curve(self.processor.get_curve())?;
         self.roi_thing.update();
-        self.image_display
-            .draw(pos, displayer, screen_size, &self.roi_thing)?;
+        // the histogram sits in the bottom left, the image is drawn top right of what's left
+        let pos = match self.processor.get_histogram() {
+            Some((heights, black_white)) if pos.height > HISTOGRAM_SIZE.1 * 2 => {
+                let histogram_pos = Rect::new(
+                    pos.x,
+                    pos.bottom() - HISTOGRAM_SIZE.1,
+                    pos.width.min(HISTOGRAM_SIZE.0),
+                    HISTOGRAM_SIZE.1,
+                );
+                draw_histogram(histogram_pos, &heights, black_white, displayer, screen_size)?;
+                Rect::new(pos.x, pos.y, pos.width, pos.height - HISTOGRAM_SIZE.1)
+            }
+            _ => pos,
+        };
+        let (src, mapping) =
+            self.image_display
+                .draw(pos, displayer, screen_size, &self.roi_thing)?;
+        if let Some(stars) = self.processor.get_stars() {
+            self.image_display.draw_stars(
+                stars,
+                &src,
+                &mapping,
+                displayer,
+                text_renderer,
+                screen_size,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn show_image(&mut self, image: Arc<ROIImage>) -> Result<()> {
+        self.image_display.set_raw(image)?;
+        // dropped frames are counted by the processor
+        self.processor.process(
+            self.image_display
+                .raw()
+                .as_ref()
+                .expect("new_raw and no raw")
+                .clone(),
+        )?;
         Ok(())
     }
 
@@ -263,7 +705,7 @@ impl CameraDisplay {
         mount: &mut Option<mount::display::MountDisplay>,
     ) -> Result<()> {
         match user_update {
-            UserUpdate::SolveFinished(ra, dec) => {
+            UserUpdate::SolveFinished(_, ra, dec) => {
                 self.solve_status = format!("{} {}", ra.fmt_hours(), dec.fmt_degrees());
                 if let Some(smount) = mount {
                     let ra_dec_mount = smount.mount.data.ra_dec_mount;
@@ -283,25 +725,84 @@ impl CameraDisplay {
                     );
                 }
             }
-            UserUpdate::CameraData(image) => {
+            UserUpdate::CameraData(_, image) => {
+                // masters are built from the raw frames
+                if let Some(ref mut master) = self.master {
+                    if master.add(image.clone()) {
+                        let master = self.master.take().expect("master was just used");
+                        master.build(&self.observation, self.send_user_update.clone(), self.id);
+                    }
+                }
+                self.calibration.apply(image)?;
+            }
+            UserUpdate::Calibrated(_, result) => {
+                let (image, calibrated) = self.calibration.user_update(result);
                 if self.save > 0 {
-                    self.save -= 1;
-                    self.save_png(&image.image)?;
+                    let image = if self.calibration.save_calibrated {
+                        calibrated.clone()
+                    } else {
+                        image
+                    };
+                    // a frame that finds the queue full is replaced by the next one, rather than
+                    // counting towards the frames asked for
+                    if self.save_image(image)? {
+                        self.save -= 1;
+                    }
                 }
-                self.image_display.set_raw(image)?;
-                let ok = self.processor.process(
-                    self.image_display
-                        .raw()
-                        .as_ref()
-                        .expect("new_raw and no raw")
-                        .clone(),
-                )?;
-                if !ok {
-                    // TODO
-                    //println!("Dropped processing frame");
+                if let Some(ref mut lucky) = self.lucky {
+                    lucky.add(calibrated.clone());
+                }
+                let showing_lucky = self.lucky.as_ref().map_or(false, Lucky::done);
+                match self.livestack {
+                    Some(ref mut livestack) => livestack.add(calibrated)?,
+                    None if showing_lucky => (),
+                    None => self.show_image(calibrated)?,
                 }
             }
-            UserUpdate::ProcessResult(process_result) => self.processor.user_update(process_result),
+            UserUpdate::LuckyResult(_, result) => {
+                if let Some(ref mut lucky) = self.lucky {
+                    lucky.user_update(result.status);
+                    if let Some(stack) = result.stack {
+                        self.show_image(stack.image.clone())?;
+                        self.lucky_stack = Some(stack);
+                        self.lucky_saved = None;
+                    }
+                }
+            }
+            UserUpdate::StackResult(_, result) => {
+                // results from a stack that has since been stopped are ignored
+                if let Some(ref mut livestack) = self.livestack {
+                    livestack.user_update(result.status);
+                    if let Some(stack) = result.stack {
+                        self.show_image(stack.image.clone())?;
+                        self.livestack_stack = Some(stack);
+                    }
+                }
+            }
+            UserUpdate::ProcessResult(_, process_result) => {
+                self.processor.user_update(process_result)
+            }
+            UserUpdate::WriterUpdate(_, writer_status) => self.writer.user_update(writer_status),
+            UserUpdate::MasterFinished(_, image_type, Ok(master)) => {
+                let path = master.path.clone();
+                self.master_status = match self.calibration.register(image_type, master) {
+                    Ok(()) => format!(
+                        "master {} saved: {}",
+                        image_type.short_name(),
+                        path.display()
+                    ),
+                    Err(err) => format!(
+                        "master {} saved to {}, but not added to the library: {}",
+                        image_type.short_name(),
+                        path.display(),
+                        err
+                    ),
+                };
+            }
+            UserUpdate::MasterFinished(_, image_type, Err(err)) => {
+                self.master_status = format!("master {} failed: {}", image_type.short_name(), err);
+            }
+            UserUpdate::DefectsFound(_, defects) => self.calibration.defects_found(defects),
             user_update => {
                 if let Some(ref mut camera) = self.camera {
                     camera.user_update(user_update);
@@ -3,10 +3,13 @@ use crate::{
         qhycamera as qhy,
         qhycamera::{ControlId, QHYCCD},
     },
+    config,
+    dms::Angle,
+    mount::thread::MountData,
     Result,
 };
 use khygl::{texture::CpuTexture, Rect};
-use std::{error::Error, ffi::CString, fmt, str, sync::Once};
+use std::{error::Error, ffi::CString, fmt, str, sync::Once, time::Duration};
 
 #[derive(Debug)]
 struct QhyError {
@@ -33,6 +36,43 @@ fn check(code: u32) -> ::std::result::Result<(), QhyError> {
     }
 }
 
+/// How and when a frame was captured, filled in by the camera thread.
+#[derive(Clone, Debug)]
+pub struct FrameMetadata {
+    // UTC
+    pub start: Option<time::OffsetDateTime>,
+    pub exposure: Option<Duration>,
+    pub gain: Option<f64>,
+    pub offset: Option<f64>,
+    // degrees C
+    pub temperature: Option<f64>,
+    pub bin: usize,
+    pub camera: String,
+    pub sequence: u64,
+    pub ra_dec: Option<(Angle, Angle)>,
+    pub az_alt: Option<(Angle, Angle)>,
+    // latitude, longitude of the observing site
+    pub location: Option<(Angle, Angle)>,
+}
+
+impl Default for FrameMetadata {
+    fn default() -> Self {
+        Self {
+            start: None,
+            exposure: None,
+            gain: None,
+            offset: None,
+            temperature: None,
+            bin: 1,
+            camera: String::new(),
+            sequence: 0,
+            ra_dec: None,
+            az_alt: None,
+            location: None,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct ROIImage {
     pub image: CpuTexture<u16>,
@@ -40,6 +80,7 @@ pub struct ROIImage {
     pub location: Rect<usize>,
     // the original sensor size
     pub original: Rect<usize>,
+    pub meta: FrameMetadata,
 }
 
 impl From<CpuTexture<u16>> for ROIImage {
@@ -49,6 +90,52 @@ impl From<CpuTexture<u16>> for ROIImage {
             image,
             location: original.clone(),
             original,
+            meta: FrameMetadata::default(),
+        }
+    }
+}
+
+/// A source of frames. The camera thread only talks to cameras through this trait, so the
+/// QHY SDK is just one backend among several.
+pub trait CameraDriver {
+    fn name(&self) -> &str;
+    fn use_live(&self) -> bool;
+    fn effective_area(&self) -> Rect<usize>;
+    fn set_roi(&mut self, roi: Rect<usize>) -> Result<()>;
+    fn unset_roi(&mut self) -> Result<()>;
+    fn control_values(&self) -> Vec<ControlValue>;
+    fn set_control(&mut self, id: ControlId, value: f64) -> Result<()>;
+    // only simulated cameras care where the telescope is pointing
+    fn set_mount_data(&mut self, _data: &MountData) {}
+    fn bin(&self) -> usize;
+    // the effective area and ROI are in binned coordinates, so this resets the ROI
+    fn set_bin(&mut self, bin: usize) -> Result<()>;
+    fn bits(&self) -> u32;
+    // 8 bit frames are still delivered as u16, with the data in the high byte
+    fn set_bits(&mut self, bits: u32) -> Result<()>;
+    fn start_single(&mut self) -> Result<()>;
+    fn stop_single(&mut self) -> Result<()>;
+    fn get_single(&mut self) -> Result<ROIImage>;
+    fn start_live(&mut self) -> Result<()>;
+    fn stop_live(&mut self) -> Result<()>;
+    // None if the next frame isn't ready yet
+    fn get_live(&mut self) -> Option<ROIImage>;
+    // switching between live and single mode may require reopening the device
+    fn reopen(self: Box<Self>, use_live: bool) -> Result<Box<dyn CameraDriver>>;
+
+    fn start(&mut self) -> Result<()> {
+        if self.use_live() {
+            self.start_live()
+        } else {
+            self.start_single()
+        }
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        if self.use_live() {
+            self.stop_live()
+        } else {
+            self.stop_single()
         }
     }
 }
@@ -61,24 +148,88 @@ fn init_qhyccd_resource() {
     })
 }
 
-pub fn autoconnect(live: bool) -> Result<Camera> {
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CameraRole {
+    Main,
+    Guide,
+}
+
+impl fmt::Display for CameraRole {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CameraRole::Main => write!(f, "main"),
+            CameraRole::Guide => write!(f, "guide"),
+        }
+    }
+}
+
+impl str::FromStr for CameraRole {
+    type Err = &'static str;
+    fn from_str(s: &str) -> ::std::result::Result<Self, Self::Err> {
+        Ok(match s {
+            "main" => CameraRole::Main,
+            "guide" => CameraRole::Guide,
+            _ => return Err("Invalid CameraRole"),
+        })
+    }
+}
+
+impl CameraRole {
+    fn config_key(self) -> String {
+        format!("camera.{}", self)
+    }
+
+    pub fn default_camera(self) -> Option<String> {
+        config::get(&self.config_key())
+    }
+
+    pub fn set_default_camera(self, name: &str) -> Result<()> {
+        config::set(&self.config_key(), name)
+    }
+}
+
+pub fn list_cameras() -> Result<Vec<CameraInfo>> {
     init_qhyccd_resource();
-    let mut best = None;
-    for id in 0..Camera::num_cameras() {
-        let info = CameraInfo::new(id)?;
-        let is163 = info.name.contains("163");
-        if best.is_none() || is163 {
-            best = Some(info);
-            if is163 {
-                break;
-            }
+    (0..Camera::num_cameras()).map(CameraInfo::new).collect()
+}
+
+// selector is either an index into list_cameras, or (part of) a camera name
+pub fn find_camera(selector: &str) -> Result<CameraInfo> {
+    let cameras = list_cameras()?;
+    if let Ok(index) = selector.parse::<usize>() {
+        return cameras
+            .into_iter()
+            .nth(index)
+            .ok_or_else(|| format!("No camera with index {}", index).into());
+    }
+    let mut partial = None;
+    for info in cameras {
+        if info.name == selector {
+            return Ok(info);
+        } else if partial.is_none() && info.name.contains(selector) {
+            partial = Some(info);
         }
     }
-    if let Some(best) = best {
-        Ok(best.open(live)?)
-    } else {
-        Err("No QHY cameras found".into())
+    partial.ok_or_else(|| format!("No camera named {}", selector).into())
+}
+
+pub fn autoconnect(role: CameraRole, live: bool) -> Result<Camera> {
+    let mut cameras = list_cameras()?;
+    if let Some(remembered) = role.default_camera() {
+        if let Some(index) = cameras.iter().position(|info| info.name == remembered) {
+            let info = cameras.remove(index);
+            cameras.insert(0, info);
+        }
     }
+    // another camera thread may already have the first choice open
+    let mut last_err = None;
+    for info in cameras {
+        match info.open(live) {
+            Ok(camera) => return Ok(camera),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| "No QHY cameras found".into()))
 }
 
 #[derive(Clone)]
@@ -114,8 +265,13 @@ pub struct Camera {
     info: CameraInfo,
     controls: Vec<Control>,
     use_live: bool,
+    // effective area at 1x1 binning
+    unbinned_area: Rect<usize>,
+    // the following are in binned coordinates
     effective_area: Rect<usize>,
     current_roi: Rect<usize>,
+    bin: usize,
+    bits: u32,
     qhyccd_mem_length_u16: usize,
 }
 
@@ -174,8 +330,11 @@ impl Camera {
                 info,
                 controls,
                 use_live,
+                unbinned_area: current_roi.clone(),
                 effective_area: current_roi.clone(),
                 current_roi,
+                bin: 1,
+                bits: 16,
                 qhyccd_mem_length_u16: len_u16,
             })
         }
@@ -185,19 +344,59 @@ impl Camera {
         &self.info
     }
 
-    pub fn use_live(&self) -> bool {
-        self.use_live
+    fn get_controls(handle: QHYCCD) -> Vec<Control> {
+        const BANNED: [ControlId; 3] = [
+            ControlId::ControlCfwport,
+            ControlId::ControlCfwslotsnum,
+            ControlId::ControlDdr,
+        ];
+        ControlId::values()
+            .iter()
+            .cloned()
+            .filter(|&id| !BANNED.iter().any(|&x| id == x))
+            .filter(|&id| unsafe { qhy::IsQHYCCDControlAvailable(handle, id) } == 0)
+            .map(|id| Control::new(handle, id))
+            .collect::<Vec<_>>()
     }
 
-    pub fn effective_area(&self) -> Rect<usize> {
-        self.effective_area.clone()
+    fn is_available(&self, id: ControlId) -> bool {
+        unsafe { qhy::IsQHYCCDControlAvailable(self.handle, id) == 0 }
     }
 
-    pub fn name(&self) -> &str {
+    fn check_frame(&self, width: u32, height: u32, bpp: u32, channels: u32) {
+        assert_eq!(bpp, self.bits);
+        assert_eq!(channels, 1);
+        assert_eq!(width as usize, self.current_roi.width);
+        assert_eq!(height as usize, self.current_roi.height);
+    }
+
+    // 8 bit frames are packed into the u16 buffer, so spread them out into the high byte of
+    // each pixel, to keep everything downstream working in 16 bit
+    fn unpack(&self, data: Vec<u16>) -> Vec<u16> {
+        if self.bits == 16 {
+            return data;
+        }
+        let len = self.current_roi.width * self.current_roi.height;
+        (0..len)
+            .map(|i| u16::from(data[i / 2].to_ne_bytes()[i % 2]) << 8)
+            .collect()
+    }
+}
+
+impl CameraDriver for Camera {
+    fn name(&self) -> &str {
         &self.info().name()
     }
 
-    pub fn set_roi(&mut self, roi: Rect<usize>) -> Result<()> {
+    fn use_live(&self) -> bool {
+        self.use_live
+    }
+
+    fn effective_area(&self) -> Rect<usize> {
+        self.effective_area.clone()
+    }
+
+    fn set_roi(&mut self, roi: Rect<usize>) -> Result<()> {
         self.current_roi = roi.clone();
         unsafe {
             Ok(check(qhy::SetQHYCCDResolution(
@@ -210,7 +409,7 @@ impl Camera {
         }
     }
 
-    pub fn unset_roi(&mut self) -> Result<()> {
+    fn unset_roi(&mut self) -> Result<()> {
         self.current_roi = self.effective_area.clone();
         unsafe {
             Ok(check(qhy::SetQHYCCDResolution(
@@ -223,26 +422,20 @@ impl Camera {
         }
     }
 
-    fn get_controls(handle: QHYCCD) -> Vec<Control> {
-        const BANNED: [ControlId; 3] = [
-            ControlId::ControlCfwport,
-            ControlId::ControlCfwslotsnum,
-            ControlId::ControlDdr,
-        ];
-        ControlId::values()
-            .iter()
-            .cloned()
-            .filter(|&id| !BANNED.iter().any(|&x| id == x))
-            .filter(|&id| unsafe { qhy::IsQHYCCDControlAvailable(handle, id) } == 0)
-            .map(|id| Control::new(handle, id))
-            .collect::<Vec<_>>()
+    fn control_values(&self) -> Vec<ControlValue> {
+        self.controls.iter().map(|c| c.to_value()).collect()
     }
 
-    pub fn controls(&self) -> &[Control] {
-        &self.controls
+    fn set_control(&mut self, id: ControlId, value: f64) -> Result<()> {
+        for control in &self.controls {
+            if control.id() == id {
+                control.set(value)?;
+            }
+        }
+        Ok(())
     }
 
-    pub fn start_single(&self) -> Result<()> {
+    fn start_single(&mut self) -> Result<()> {
         let single = unsafe { qhy::ExpQHYCCDSingleFrame(self.handle) };
         // QHYCCD_READ_DIRECTLY
         if single != 0x2001 {
@@ -251,11 +444,11 @@ impl Camera {
         Ok(())
     }
 
-    pub fn stop_single(&self) -> Result<()> {
+    fn stop_single(&mut self) -> Result<()> {
         unsafe { Ok(check(qhy::CancelQHYCCDExposingAndReadout(self.handle))?) }
     }
 
-    pub fn get_single(&self) -> Result<ROIImage> {
+    fn get_single(&mut self) -> Result<ROIImage> {
         unsafe {
             // GetQHYCCDExposureRemaining seems to be unreliable, so just block
             let mut width = 0;
@@ -271,27 +464,25 @@ impl Camera {
                 &mut channels,
                 data.as_mut_ptr() as _,
             ))?;
-            assert_eq!(bpp, 16);
-            assert_eq!(channels, 1);
-            assert_eq!(width as usize, self.current_roi.width);
-            assert_eq!(height as usize, self.current_roi.height);
+            self.check_frame(width, height, bpp, channels);
             Ok(ROIImage {
-                image: CpuTexture::new(data, (width as usize, height as usize)),
+                image: CpuTexture::new(self.unpack(data), (width as usize, height as usize)),
                 location: self.current_roi.clone(),
                 original: self.effective_area.clone(),
+                meta: FrameMetadata::default(),
             })
         }
     }
 
-    pub fn start_live(&self) -> Result<()> {
+    fn start_live(&mut self) -> Result<()> {
         unsafe { Ok(check(qhy::BeginQHYCCDLive(self.handle))?) }
     }
 
-    pub fn stop_live(&self) -> Result<()> {
+    fn stop_live(&mut self) -> Result<()> {
         unsafe { Ok(check(qhy::StopQHYCCDLive(self.handle))?) }
     }
 
-    pub fn get_live(&self) -> Option<ROIImage> {
+    fn get_live(&mut self) -> Option<ROIImage> {
         unsafe {
             let mut width = 0;
             let mut height = 0;
@@ -310,33 +501,74 @@ impl Camera {
                 // function will fail if image isn't ready yet
                 None
             } else {
-                assert_eq!(bpp, 16);
-                assert_eq!(channels, 1);
-                assert_eq!(width as usize, self.current_roi.width);
-                assert_eq!(height as usize, self.current_roi.height);
+                self.check_frame(width, height, bpp, channels);
                 Some(ROIImage {
-                    image: CpuTexture::new(data, (width as usize, height as usize)),
+                    image: CpuTexture::new(self.unpack(data), (width as usize, height as usize)),
                     location: self.current_roi.clone(),
                     original: self.effective_area.clone(),
+                    meta: FrameMetadata::default(),
                 })
             }
         }
     }
 
-    pub fn start(&self) -> Result<()> {
-        if self.use_live {
-            self.start_live()
-        } else {
-            self.start_single()
+    fn bin(&self) -> usize {
+        self.bin
+    }
+
+    fn set_bin(&mut self, bin: usize) -> Result<()> {
+        let mode = match bin {
+            1 => ControlId::CamBin1x1mode,
+            2 => ControlId::CamBin2x2mode,
+            3 => ControlId::CamBin3x3mode,
+            4 => ControlId::CamBin4x4mode,
+            _ => return Err(format!("Invalid bin mode {}", bin).into()),
+        };
+        if !self.is_available(mode) {
+            return Err(format!("Camera does not support {}x{} binning", bin, bin).into());
         }
+        unsafe { check(qhy::SetQHYCCDBinMode(self.handle, bin as u32, bin as u32))? };
+        self.bin = bin;
+        self.effective_area = Rect::new(
+            self.unbinned_area.x / bin,
+            self.unbinned_area.y / bin,
+            self.unbinned_area.width / bin,
+            self.unbinned_area.height / bin,
+        );
+        self.unset_roi()
+    }
+
+    fn bits(&self) -> u32 {
+        self.bits
+    }
+
+    fn set_bits(&mut self, bits: u32) -> Result<()> {
+        let mode = match bits {
+            8 => ControlId::Cam8bits,
+            16 => ControlId::Cam16bits,
+            _ => return Err(format!("Invalid bit depth {}", bits).into()),
+        };
+        if !self.is_available(mode) {
+            return Err(format!("Camera does not support {} bit mode", bits).into());
+        }
+        unsafe { check(qhy::SetQHYCCDBitsMode(self.handle, bits))? };
+        self.bits = bits;
+        Ok(())
     }
 
-    pub fn stop(&self) -> Result<()> {
-        if self.use_live {
-            self.stop_live()
-        } else {
-            self.stop_single()
+    fn reopen(self: Box<Self>, use_live: bool) -> Result<Box<dyn CameraDriver>> {
+        let info = self.info.clone();
+        let (bin, bits) = (self.bin, self.bits);
+        // the old handle must be closed before the device can be opened again
+        drop(self);
+        let mut camera = info.open(use_live)?;
+        if bin != 1 {
+            camera.set_bin(bin)?;
+        }
+        if bits != 16 {
+            camera.set_bits(bits)?;
         }
+        Ok(Box::new(camera))
     }
 }
 
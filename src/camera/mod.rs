@@ -1,4 +1,10 @@
+pub mod cooler;
 pub mod display;
 pub mod interface;
 pub mod qhycamera;
+pub mod recorder;
+pub mod simulated;
 pub mod thread;
+
+// identifies which camera a UserUpdate belongs to
+pub type CameraId = usize;
@@ -0,0 +1,241 @@
+use crate::{camera::interface::ROIImage, Result};
+use std::{
+    fs::File,
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread::spawn,
+    time::{Duration, Instant},
+};
+
+// bytes of frames waiting to be written before new ones are dropped. Counted in bytes rather
+// than frames, since full frames of large sensors run to tens of megabytes each.
+const QUEUE_BYTES: usize = 256 * 1024 * 1024;
+const HEADER_SIZE: usize = 178;
+const FRAME_COUNT_OFFSET: u64 = 38;
+// .NET ticks (100ns since 0001-01-01) at the unix epoch, which is what SER timestamps use
+const UNIX_EPOCH_TICKS: i64 = 621_355_968_000_000_000;
+
+#[derive(Clone, Copy, Debug)]
+pub enum RecordLimit {
+    Frames(u64),
+    Time(Duration),
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct RecordStatus {
+    pub path: Option<PathBuf>,
+    pub written: u64,
+    pub dropped: u64,
+    pub bytes: u64,
+    pub started: Option<Instant>,
+    pub ended: Option<Instant>,
+    pub finished: bool,
+    pub error: Option<String>,
+}
+
+impl RecordStatus {
+    // (frames per second, megabytes per second)
+    pub fn throughput(&self) -> (f64, f64) {
+        let seconds = match self.started {
+            Some(started) => (self.ended.unwrap_or_else(Instant::now) - started).as_secs_f64(),
+            None => return (0.0, 0.0),
+        };
+        if seconds <= 0.0 {
+            return (0.0, 0.0);
+        }
+        (
+            self.written as f64 / seconds,
+            self.bytes as f64 / seconds / 1_000_000.0,
+        )
+    }
+}
+
+fn ticks(time: time::OffsetDateTime) -> i64 {
+    UNIX_EPOCH_TICKS + time.timestamp() * 10_000_000 + i64::from(time.nanosecond()) / 100
+}
+
+fn fixed_string(value: &str) -> [u8; 40] {
+    let mut result = [b' '; 40];
+    for (dest, &src) in result.iter_mut().zip(value.as_bytes()) {
+        *dest = src;
+    }
+    result
+}
+
+fn header(width: usize, height: usize, instrument: &str) -> Vec<u8> {
+    let now = time::OffsetDateTime::now_local();
+    let local_ticks = ticks(now) + i64::from(now.offset().as_seconds()) * 10_000_000;
+    let mut header = Vec::with_capacity(HEADER_SIZE);
+    header.extend_from_slice(b"LUCAM-RECORDER");
+    // LuID
+    header.extend_from_slice(&0i32.to_le_bytes());
+    // ColorID: mono
+    header.extend_from_slice(&0i32.to_le_bytes());
+    // the spec says 1 means little endian, but every reader in practice treats 0 as little endian
+    header.extend_from_slice(&0i32.to_le_bytes());
+    header.extend_from_slice(&(width as i32).to_le_bytes());
+    header.extend_from_slice(&(height as i32).to_le_bytes());
+    header.extend_from_slice(&16i32.to_le_bytes());
+    // frame count, filled in at the end
+    header.extend_from_slice(&0i32.to_le_bytes());
+    header.extend_from_slice(&fixed_string(""));
+    header.extend_from_slice(&fixed_string(instrument));
+    header.extend_from_slice(&fixed_string(""));
+    header.extend_from_slice(&local_ticks.to_le_bytes());
+    header.extend_from_slice(&ticks(now).to_le_bytes());
+    assert_eq!(header.len(), HEADER_SIZE);
+    header
+}
+
+fn frame_bytes(frame: &ROIImage) -> usize {
+    frame.image.data().len() * std::mem::size_of::<u16>()
+}
+
+fn write_frames(
+    path: &Path,
+    recv: mpsc::Receiver<Arc<ROIImage>>,
+    instrument: &str,
+    status: &Mutex<RecordStatus>,
+    queued_bytes: &AtomicUsize,
+) -> Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    let mut size = None;
+    let mut timestamps = Vec::new();
+    let mut buf = Vec::new();
+    let mut result = Ok(());
+    while let Ok(frame) = recv.recv() {
+        queued_bytes.fetch_sub(frame_bytes(&frame), Ordering::SeqCst);
+        let frame_size = frame.image.size;
+        match size {
+            None => {
+                file.write_all(&header(frame_size.0, frame_size.1, instrument))?;
+                size = Some(frame_size);
+            }
+            Some(size) if size != frame_size => {
+                // stop, but still leave a readable file behind
+                result = Err("Frame size changed during recording".into());
+                break;
+            }
+            Some(_) => (),
+        }
+        buf.clear();
+        for &value in frame.image.data() {
+            buf.extend_from_slice(&value.to_le_bytes());
+        }
+        file.write_all(&buf)?;
+        let start = frame
+            .meta
+            .start
+            .unwrap_or_else(time::OffsetDateTime::now_utc);
+        timestamps.push(ticks(start));
+        let mut status = status.lock().unwrap();
+        status.written += 1;
+        status.bytes += buf.len() as u64;
+    }
+    // the frame count isn't known until the end, so patch it into the header
+    if size.is_some() {
+        for timestamp in &timestamps {
+            file.write_all(&timestamp.to_le_bytes())?;
+        }
+        file.seek(SeekFrom::Start(FRAME_COUNT_OFFSET))?;
+        file.write_all(&(timestamps.len() as u32).to_le_bytes())?;
+    }
+    file.flush()?;
+    result
+}
+
+/// Streams frames into a SER video on its own thread. Frames that arrive faster than they
+/// can be written are dropped and counted.
+pub struct Recorder {
+    // None once the limit is reached or recording is stopped, which lets the thread finish
+    send: Option<mpsc::Sender<Arc<ROIImage>>>,
+    queued_bytes: Arc<AtomicUsize>,
+    status: Arc<Mutex<RecordStatus>>,
+    limit: RecordLimit,
+    started: Instant,
+    offered: u64,
+}
+
+impl Recorder {
+    pub fn new(path: PathBuf, limit: RecordLimit, instrument: String) -> Self {
+        let (send, recv) = mpsc::channel();
+        let queued_bytes = Arc::new(AtomicUsize::new(0));
+        let thread_queued_bytes = queued_bytes.clone();
+        let status = Arc::new(Mutex::new(RecordStatus {
+            path: Some(path.clone()),
+            started: Some(Instant::now()),
+            ..RecordStatus::default()
+        }));
+        let thread_status = status.clone();
+        spawn(move || {
+            let result = write_frames(
+                &path,
+                recv,
+                &instrument,
+                &thread_status,
+                &thread_queued_bytes,
+            );
+            let mut status = thread_status.lock().unwrap();
+            status.finished = true;
+            status.ended = Some(Instant::now());
+            if let Err(err) = result {
+                status.error = Some(err.to_string());
+            }
+        });
+        Self {
+            send: Some(send),
+            queued_bytes,
+            status,
+            limit,
+            started: Instant::now(),
+            offered: 0,
+        }
+    }
+
+    pub fn done(&self) -> bool {
+        self.send.is_none()
+    }
+
+    pub fn stop(&mut self) {
+        self.send = None;
+    }
+
+    /// Stops once the limit is reached. Called from the camera loop as well as for each frame,
+    /// so a time limited recording still finishes its file when frames stop arriving.
+    pub fn check_limit(&mut self) {
+        let limit_reached = match self.limit {
+            RecordLimit::Frames(frames) => self.offered >= frames,
+            RecordLimit::Time(duration) => Instant::now() - self.started >= duration,
+        };
+        if limit_reached {
+            self.stop();
+        }
+    }
+
+    pub fn record(&mut self, frame: &Arc<ROIImage>) {
+        self.check_limit();
+        let send = match self.send {
+            Some(ref send) => send,
+            None => return,
+        };
+        self.offered += 1;
+        let bytes = frame_bytes(frame);
+        if self.queued_bytes.load(Ordering::SeqCst) + bytes > QUEUE_BYTES {
+            self.status.lock().unwrap().dropped += 1;
+            return;
+        }
+        self.queued_bytes.fetch_add(bytes, Ordering::SeqCst);
+        // the writer thread stopped with an error
+        if send.send(frame.clone()).is_err() {
+            self.stop();
+        }
+    }
+
+    pub fn status(&self) -> RecordStatus {
+        self.status.lock().unwrap().clone()
+    }
+}
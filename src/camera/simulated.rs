@@ -0,0 +1,504 @@
+use crate::{
+    camera::{
+        interface::{CameraDriver, ControlValue, FrameMetadata, ROIImage},
+        qhycamera::{ControlId, EXPOSURE_FACTOR},
+    },
+    mount::thread::MountData,
+    Result,
+};
+use khygl::{texture::CpuTexture, Rect};
+use std::{
+    f64::consts::PI,
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+pub const SIMULATOR_NAME: &str = "Simulator";
+// arcseconds per pixel, matches what platesolve tells solve-field to expect
+const PIXEL_SCALE: f64 = 1.0;
+const SENSOR_SIZE: (usize, usize) = (1600, 1200);
+// size of one procedurally generated patch of sky, in degrees
+const CELL_SIZE: f64 = 0.2;
+const STARS_PER_CELL: usize = 15;
+const FAINTEST_MAGNITUDE: f64 = 15.0;
+// electrons per second of an 8th magnitude star
+const MAG8_FLUX: f64 = 2e5;
+const SKY_FLUX: f64 = 20.0;
+const READ_NOISE: f64 = 3.0;
+const HOT_PIXEL_FRACTION: f64 = 0.0005;
+const MIN_LIVE_INTERVAL: Duration = Duration::from_millis(10);
+// the simulated cooler, degrees C
+const AMBIENT: f64 = 20.0;
+const MAX_COOLING: f64 = 35.0;
+const COOLER_TIME_CONSTANT: f64 = 90.0;
+
+// xorshift64*
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Self(splitmix(seed) | 1)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.0 = x;
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    // (0, 1]
+    fn next_f64(&mut self) -> f64 {
+        ((self.next_u64() >> 11) + 1) as f64 / (1u64 << 53) as f64
+    }
+
+    fn gaussian(&mut self) -> f64 {
+        // Box-Muller
+        let u1 = self.next_f64();
+        let u2 = self.next_f64();
+        (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos()
+    }
+
+    fn poisson(&mut self, lambda: f64) -> f64 {
+        if lambda <= 0.0 {
+            0.0
+        } else if lambda < 30.0 {
+            // Knuth
+            let limit = (-lambda).exp();
+            let mut k = 0.0;
+            let mut p = self.next_f64();
+            while p > limit {
+                k += 1.0;
+                p *= self.next_f64();
+            }
+            k
+        } else {
+            (lambda + lambda.sqrt() * self.gaussian()).max(0.0)
+        }
+    }
+}
+
+fn splitmix(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Psf {
+    Gaussian,
+    Moffat { beta: f64 },
+}
+
+impl Default for Psf {
+    fn default() -> Self {
+        Psf::Moffat { beta: 4.765 }
+    }
+}
+
+struct Star {
+    ra: f64,
+    dec: f64,
+    flux: f64,
+}
+
+fn stars_in_cell(cell_ra: i64, cell_dec: i64) -> impl Iterator<Item = Star> {
+    let mut rng = Rng::new(((cell_ra as u64) << 32) ^ (cell_dec as u64 & 0xffff_ffff));
+    (0..STARS_PER_CELL).map(move |_| {
+        let ra = (cell_ra as f64 + rng.next_f64()) * CELL_SIZE;
+        let dec = (cell_dec as f64 + rng.next_f64()) * CELL_SIZE;
+        // N(<m) ~ 10^(0.4m)
+        let magnitude = FAINTEST_MAGNITUDE + rng.next_f64().log10() / 0.4;
+        let flux = MAG8_FLUX * 10f64.powf(-0.4 * (magnitude - 8.0));
+        Star { ra, dec, flux }
+    })
+}
+
+/// A fake camera that renders a procedurally generated star field, for developing without
+/// hardware attached.
+pub struct SimulatedCamera {
+    use_live: bool,
+    // binned coordinates
+    effective_area: Rect<usize>,
+    current_roi: Rect<usize>,
+    bin: usize,
+    bits: u32,
+    exposure: f64,
+    gain: f64,
+    offset: f64,
+    psf: Psf,
+    // full width at half maximum, in pixels
+    seeing: f64,
+    // electrons per second per pixel, across the whole sensor in x and y
+    gradient: (f64, f64),
+    hot_pixels: Vec<((usize, usize), f64)>,
+    pointing: (f64, f64),
+    cooler_target: Option<f64>,
+    // (when, temperature) of the last cooler change, temperature decays towards the target
+    cooler_start: (Instant, f64),
+    rng: Rng,
+    exposure_start: Instant,
+    next_live: Instant,
+}
+
+impl SimulatedCamera {
+    pub fn new(use_live: bool, psf: Psf) -> Self {
+        let effective_area = Rect::new(0, 0, SENSOR_SIZE.0, SENSOR_SIZE.1);
+        let mut rng = Rng::new(0x5c09_1e);
+        let num_hot = (SENSOR_SIZE.0 * SENSOR_SIZE.1) as f64 * HOT_PIXEL_FRACTION;
+        let hot_pixels = (0..num_hot as usize)
+            .map(|_| {
+                let x = (rng.next_u64() % SENSOR_SIZE.0 as u64) as usize;
+                let y = (rng.next_u64() % SENSOR_SIZE.1 as u64) as usize;
+                // dark current, electrons per second
+                let rate = 50.0 + 5000.0 * rng.next_f64().powi(4);
+                ((x, y), rate)
+            })
+            .collect();
+        Self {
+            use_live,
+            current_roi: effective_area.clone(),
+            effective_area,
+            bin: 1,
+            bits: 16,
+            exposure: EXPOSURE_FACTOR,
+            gain: 0.0,
+            offset: 10.0,
+            psf,
+            seeing: 2.5,
+            gradient: (8.0, 3.0),
+            hot_pixels,
+            // M42
+            pointing: (83.82, -5.39),
+            cooler_target: None,
+            cooler_start: (Instant::now(), AMBIENT),
+            rng,
+            exposure_start: Instant::now(),
+            next_live: Instant::now(),
+        }
+    }
+
+    fn temperature(&self) -> f64 {
+        let goal = self
+            .cooler_target
+            .map_or(AMBIENT, |target| target.max(AMBIENT - MAX_COOLING));
+        let (start_time, start_temperature) = self.cooler_start;
+        let elapsed = start_time.elapsed().as_secs_f64();
+        goal + (start_temperature - goal) * (-elapsed / COOLER_TIME_CONSTANT).exp()
+    }
+
+    fn pwm(&self) -> f64 {
+        if self.cooler_target.is_some() {
+            ((AMBIENT - self.temperature()) / MAX_COOLING * 255.0)
+                .max(0.0)
+                .min(255.0)
+        } else {
+            0.0
+        }
+    }
+
+    fn set_cooler_target(&mut self, target: Option<f64>) {
+        self.cooler_start = (Instant::now(), self.temperature());
+        self.cooler_target = target;
+    }
+
+    fn exposure_duration(&self) -> Duration {
+        Duration::from_secs_f64((self.exposure / EXPOSURE_FACTOR).max(0.0))
+    }
+
+    // ADU per electron
+    fn system_gain(&self) -> f64 {
+        0.25 * 10f64.powf(self.gain / 200.0)
+    }
+
+    fn psf(&self, dist2: f64) -> f64 {
+        match self.psf {
+            Psf::Gaussian => {
+                let sigma = self.seeing / (2.0 * (2.0 * 2f64.ln()).sqrt());
+                let two_sigma2 = 2.0 * sigma * sigma;
+                (-dist2 / two_sigma2).exp() / (PI * two_sigma2)
+            }
+            Psf::Moffat { beta } => {
+                let alpha = self.seeing / (2.0 * (2f64.powf(1.0 / beta) - 1.0).sqrt());
+                let alpha2 = alpha * alpha;
+                (beta - 1.0) / (PI * alpha2) * (1.0 + dist2 / alpha2).powf(-beta)
+            }
+        }
+    }
+
+    // in unbinned sensor pixels
+    fn sky_to_sensor(&self, ra: f64, dec: f64) -> (f64, f64) {
+        let mut delta_ra = ra - self.pointing.0;
+        if delta_ra > 180.0 {
+            delta_ra -= 360.0;
+        } else if delta_ra < -180.0 {
+            delta_ra += 360.0;
+        }
+        let delta_ra = delta_ra * self.pointing.1.to_radians().cos();
+        let delta_dec = dec - self.pointing.1;
+        (
+            SENSOR_SIZE.0 as f64 / 2.0 - delta_ra * 3600.0 / PIXEL_SCALE,
+            SENSOR_SIZE.1 as f64 / 2.0 - delta_dec * 3600.0 / PIXEL_SCALE,
+        )
+    }
+
+    fn visible_stars(&self) -> Vec<((f64, f64), f64)> {
+        let half_fov = SENSOR_SIZE.0.max(SENSOR_SIZE.1) as f64 / 2.0 * PIXEL_SCALE / 3600.0;
+        let cos_dec = self.pointing.1.to_radians().cos().max(0.01);
+        let ra_range = (half_fov / cos_dec).min(180.0);
+        let dec_cells = ((self.pointing.1 - half_fov) / CELL_SIZE).floor() as i64
+            ..=((self.pointing.1 + half_fov) / CELL_SIZE).floor() as i64;
+        let ra_cells = ((self.pointing.0 - ra_range) / CELL_SIZE).floor() as i64
+            ..=((self.pointing.0 + ra_range) / CELL_SIZE).floor() as i64;
+        let cells_around = (360.0 / CELL_SIZE).round() as i64;
+        let mut result = Vec::new();
+        for cell_dec in dec_cells {
+            for cell_ra in ra_cells.clone() {
+                for star in stars_in_cell(cell_ra.rem_euclid(cells_around), cell_dec) {
+                    let pos = self.sky_to_sensor(star.ra, star.dec);
+                    result.push((pos, star.flux));
+                }
+            }
+        }
+        result
+    }
+
+    fn render(&mut self) -> ROIImage {
+        let bin = self.bin;
+        let roi = self.current_roi.clone();
+        // the sensor itself is simulated unbinned, then summed into bins
+        let sensor = Rect::new(roi.x * bin, roi.y * bin, roi.width * bin, roi.height * bin);
+        let sensor_size = (self.effective_area.width * bin, self.effective_area.height * bin);
+        let seconds = self.exposure / EXPOSURE_FACTOR;
+        let mut electrons = vec![0.0; sensor.width * sensor.height];
+        for y in 0..sensor.height {
+            for x in 0..sensor.width {
+                let fx = (x + sensor.x) as f64 / sensor_size.0 as f64 - 0.5;
+                let fy = (y + sensor.y) as f64 / sensor_size.1 as f64 - 0.5;
+                let sky = SKY_FLUX + self.gradient.0 * fx + self.gradient.1 * fy;
+                electrons[y * sensor.width + x] = sky.max(0.0) * seconds;
+            }
+        }
+        let radius = (self.seeing * 4.0).ceil() as isize;
+        for ((star_x, star_y), flux) in self.visible_stars() {
+            let center_x = star_x.round() as isize;
+            let center_y = star_y.round() as isize;
+            for y in (center_y - radius)..=(center_y + radius) {
+                for x in (center_x - radius)..=(center_x + radius) {
+                    let local_x = x - sensor.x as isize;
+                    let local_y = y - sensor.y as isize;
+                    if local_x < 0
+                        || local_y < 0
+                        || local_x >= sensor.width as isize
+                        || local_y >= sensor.height as isize
+                    {
+                        continue;
+                    }
+                    let dx = x as f64 - star_x;
+                    let dy = y as f64 - star_y;
+                    let value = flux * seconds * self.psf(dx * dx + dy * dy);
+                    electrons[local_y as usize * sensor.width + local_x as usize] += value;
+                }
+            }
+        }
+        // dark current doubles every 6 degrees C
+        let dark_scale = 2f64.powf((self.temperature() - AMBIENT) / 6.0);
+        for &((x, y), rate) in &self.hot_pixels {
+            if x >= sensor.x && y >= sensor.y && x < sensor.right() && y < sensor.bottom() {
+                let dark = rate * dark_scale * seconds;
+                electrons[(y - sensor.y) * sensor.width + (x - sensor.x)] += dark;
+            }
+        }
+        let mut binned = vec![0.0; roi.width * roi.height];
+        for y in 0..sensor.height {
+            for x in 0..sensor.width {
+                let value = self.rng.poisson(electrons[y * sensor.width + x]);
+                binned[(y / bin) * roi.width + (x / bin)] += value;
+            }
+        }
+        let system_gain = self.system_gain();
+        let pedestal = self.offset * 16.0;
+        let mask = if self.bits == 8 { 0xff00 } else { 0xffff };
+        let mut data = Vec::with_capacity(binned.len());
+        for value in binned {
+            let signal = value + READ_NOISE * self.rng.gaussian();
+            let adu = signal * system_gain + pedestal;
+            let adu = if adu <= 0.0 {
+                0
+            } else if adu >= f64::from(u16::max_value()) {
+                u16::max_value()
+            } else {
+                adu as u16
+            };
+            data.push(adu & mask);
+        }
+        ROIImage {
+            image: CpuTexture::new(data, (roi.width, roi.height)),
+            location: roi,
+            original: self.effective_area.clone(),
+            meta: FrameMetadata::default(),
+        }
+    }
+
+    fn control(id: ControlId, value: f64, min: f64, max: f64, step: f64) -> ControlValue {
+        ControlValue {
+            id,
+            value,
+            min,
+            max,
+            step,
+            readonly: false,
+            interesting: ControlId::is_interesting(id),
+        }
+    }
+}
+
+impl CameraDriver for SimulatedCamera {
+    fn name(&self) -> &str {
+        SIMULATOR_NAME
+    }
+
+    fn use_live(&self) -> bool {
+        self.use_live
+    }
+
+    fn effective_area(&self) -> Rect<usize> {
+        self.effective_area.clone()
+    }
+
+    fn set_roi(&mut self, roi: Rect<usize>) -> Result<()> {
+        if roi.right() > self.effective_area.right() || roi.bottom() > self.effective_area.bottom()
+        {
+            return Err("ROI outside of sensor".into());
+        }
+        self.current_roi = roi;
+        Ok(())
+    }
+
+    fn unset_roi(&mut self) -> Result<()> {
+        self.current_roi = self.effective_area.clone();
+        Ok(())
+    }
+
+    fn control_values(&self) -> Vec<ControlValue> {
+        vec![
+            Self::control(
+                ControlId::ControlExposure,
+                self.exposure,
+                1.0,
+                3600.0 * EXPOSURE_FACTOR,
+                1.0,
+            ),
+            Self::control(ControlId::ControlGain, self.gain, 0.0, 400.0, 1.0),
+            Self::control(ControlId::ControlOffset, self.offset, 0.0, 255.0, 1.0),
+            ControlValue {
+                readonly: true,
+                ..Self::control(ControlId::ControlCurtemp, self.temperature(), 0.0, 0.0, 0.0)
+            },
+            ControlValue {
+                readonly: true,
+                ..Self::control(ControlId::ControlCurpwm, self.pwm(), 0.0, 0.0, 0.0)
+            },
+            Self::control(
+                ControlId::ControlCooler,
+                self.cooler_target.unwrap_or(AMBIENT),
+                AMBIENT - MAX_COOLING,
+                AMBIENT,
+                0.1,
+            ),
+            Self::control(ControlId::ControlManulpwm, self.pwm(), 0.0, 255.0, 1.0),
+        ]
+    }
+
+    fn set_control(&mut self, id: ControlId, value: f64) -> Result<()> {
+        match id {
+            ControlId::ControlExposure => self.exposure = value.max(1.0),
+            ControlId::ControlGain => self.gain = value.max(0.0).min(400.0),
+            ControlId::ControlOffset => self.offset = value.max(0.0).min(255.0),
+            ControlId::ControlCooler => self.set_cooler_target(Some(value)),
+            ControlId::ControlManulpwm if value <= 0.0 => self.set_cooler_target(None),
+            ControlId::ControlManulpwm => {
+                let target = AMBIENT - value.min(255.0) / 255.0 * MAX_COOLING;
+                self.set_cooler_target(Some(target))
+            }
+            _ => return Err(format!("Simulator has no control {}", id).into()),
+        }
+        Ok(())
+    }
+
+    fn set_mount_data(&mut self, data: &MountData) {
+        let (ra, dec) = data.ra_dec_real;
+        self.pointing = (ra.degrees(), dec.signed_degrees());
+    }
+
+    fn bin(&self) -> usize {
+        self.bin
+    }
+
+    fn set_bin(&mut self, bin: usize) -> Result<()> {
+        if !(1..=4).contains(&bin) {
+            return Err(format!("Invalid bin mode {}", bin).into());
+        }
+        self.bin = bin;
+        self.effective_area = Rect::new(0, 0, SENSOR_SIZE.0 / bin, SENSOR_SIZE.1 / bin);
+        self.unset_roi()
+    }
+
+    fn bits(&self) -> u32 {
+        self.bits
+    }
+
+    fn set_bits(&mut self, bits: u32) -> Result<()> {
+        if bits != 8 && bits != 16 {
+            return Err(format!("Invalid bit depth {}", bits).into());
+        }
+        self.bits = bits;
+        Ok(())
+    }
+
+    fn start_single(&mut self) -> Result<()> {
+        self.exposure_start = Instant::now();
+        Ok(())
+    }
+
+    fn stop_single(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn get_single(&mut self) -> Result<ROIImage> {
+        let end = self.exposure_start + self.exposure_duration();
+        let now = Instant::now();
+        if end > now {
+            sleep(end - now);
+        }
+        Ok(self.render())
+    }
+
+    fn start_live(&mut self) -> Result<()> {
+        self.next_live = Instant::now() + self.exposure_duration().max(MIN_LIVE_INTERVAL);
+        Ok(())
+    }
+
+    fn stop_live(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn get_live(&mut self) -> Option<ROIImage> {
+        let now = Instant::now();
+        if now < self.next_live {
+            None
+        } else {
+            self.next_live = now + self.exposure_duration().max(MIN_LIVE_INTERVAL);
+            Some(self.render())
+        }
+    }
+
+    fn reopen(self: Box<Self>, use_live: bool) -> Result<Box<dyn CameraDriver>> {
+        let mut camera = *self;
+        camera.use_live = use_live;
+        Ok(Box::new(camera))
+    }
+}
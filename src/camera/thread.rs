@@ -1,6 +1,19 @@
-use crate::{camera, camera::qhycamera::ControlId, Result, SendUserUpdate, UserUpdate};
+use crate::{
+    camera,
+    camera::{
+        cooler::{Cooler, CoolerStatus},
+        interface::{CameraDriver, CameraRole, ControlValue, FrameMetadata},
+        qhycamera::{ControlId, EXPOSURE_FACTOR},
+        recorder::{RecordLimit, RecordStatus, Recorder},
+        simulated::{Psf, SimulatedCamera, SIMULATOR_NAME},
+        CameraId,
+    },
+    mount::thread::MountData,
+    Result, SendUserUpdate, UserUpdate,
+};
 use khygl::Rect;
 use std::{
+    path::PathBuf,
     sync::{mpsc, Arc},
     thread::spawn,
     time::{Duration, Instant},
@@ -12,44 +25,94 @@ enum CameraCommand {
     Stop,
     ToggleLive,
     SetROI(Option<Rect<usize>>),
+    SetBin(usize),
+    SetBits(u32),
+    SetMountData(MountData),
+    Simulate(Psf),
+    List,
+    Select(String),
+    Cool(f64, f64),
+    Warm(f64),
+    Record(PathBuf, RecordLimit),
+    StopRecording,
 }
 
 #[derive(Clone, Debug)]
 pub struct CameraData {
     pub controls: Vec<camera::interface::ControlValue>,
     pub name: String,
+    pub role: CameraRole,
+    // filled in by the cameras command
+    pub available: Vec<String>,
     pub cmd_status: String,
     pub running: bool,
     pub is_live: bool,
+    pub bin: usize,
+    pub bits: u32,
+    pub cooler: CoolerStatus,
+    // the current or most recent recording
+    pub recording: Option<RecordStatus>,
     pub exposure_start: Instant,
     pub exposure_duration: Duration,
     pub effective_area: Option<Rect<usize>>,
 }
 
+impl CameraData {
+    fn new(role: CameraRole) -> Self {
+        Self {
+            controls: Vec::new(),
+            name: String::new(),
+            role,
+            available: Vec::new(),
+            cmd_status: String::new(),
+            running: false,
+            is_live: false,
+            bin: 1,
+            bits: 16,
+            cooler: CoolerStatus::default(),
+            recording: None,
+            exposure_start: Instant::now(),
+            exposure_duration: Duration::from_secs(0),
+            effective_area: None,
+        }
+    }
+}
+
+// the simulator is only used when asked for, never in place of a camera that failed to open
+pub fn connect(role: CameraRole) -> Result<Box<dyn CameraDriver>> {
+    if role.default_camera().map_or(false, |name| name == SIMULATOR_NAME) {
+        return Ok(Box::new(SimulatedCamera::new(false, Psf::default())));
+    }
+    Ok(Box::new(camera::interface::autoconnect(role, false)?))
+}
+
 pub struct CameraAsync {
     send: mpsc::Sender<CameraCommand>,
     pub data: CameraData,
 }
 
 impl CameraAsync {
-    pub fn new(send_user_update: SendUserUpdate) -> Self {
+    pub fn new(
+        send_user_update: SendUserUpdate,
+        id: CameraId,
+        role: CameraRole,
+        connect: impl FnOnce() -> Result<Box<dyn CameraDriver>> + Send + 'static,
+    ) -> Self {
         let (send_cmd, recv_cmd) = mpsc::channel();
-        spawn(move || match run(recv_cmd, send_user_update) {
-            Ok(()) => (),
-            Err(err) => println!("Camera thread error: {}", err),
+        let send_error = send_user_update.clone();
+        spawn(move || {
+            if let Err(err) = run(recv_cmd, send_user_update, id, role, connect) {
+                let data = CameraData {
+                    cmd_status: format!("Camera thread stopped: {}", err),
+                    ..CameraData::new(role)
+                };
+                // nothing left to report to if the UI is gone
+                let _ = send_error.send_event(UserUpdate::CameraUpdate(id, data));
+            }
         });
         Self {
             send: send_cmd,
-            data: CameraData {
-                controls: Vec::new(),
-                name: String::new(),
-                cmd_status: String::new(),
-                running: false,
-                is_live: false,
-                exposure_start: Instant::now(),
-                exposure_duration: Duration::from_secs(0),
-                effective_area: None,
-            },
+            data: CameraData::new(role),
         }
     }
 
@@ -75,18 +138,107 @@ impl CameraAsync {
         self.send.send(CameraCommand::SetROI(roi)).map_err(|_| ())
     }
 
+    pub fn set_bin(&self, bin: usize) -> std::result::Result<(), ()> {
+        self.send.send(CameraCommand::SetBin(bin)).map_err(|_| ())
+    }
+
+    pub fn set_bits(&self, bits: u32) -> std::result::Result<(), ()> {
+        self.send.send(CameraCommand::SetBits(bits)).map_err(|_| ())
+    }
+
+    pub fn set_mount_data(&self, data: MountData) -> std::result::Result<(), ()> {
+        self.send
+            .send(CameraCommand::SetMountData(data))
+            .map_err(|_| ())
+    }
+
+    pub fn simulate(&self, psf: Psf) -> std::result::Result<(), ()> {
+        self.send.send(CameraCommand::Simulate(psf)).map_err(|_| ())
+    }
+
+    pub fn list(&self) -> std::result::Result<(), ()> {
+        self.send.send(CameraCommand::List).map_err(|_| ())
+    }
+
+    pub fn select(&self, selector: String) -> std::result::Result<(), ()> {
+        self.send
+            .send(CameraCommand::Select(selector))
+            .map_err(|_| ())
+    }
+
+    // rate is in degrees C per minute
+    pub fn cool(&self, target: f64, rate: f64) -> std::result::Result<(), ()> {
+        self.send
+            .send(CameraCommand::Cool(target, rate))
+            .map_err(|_| ())
+    }
+
+    pub fn warm(&self, rate: f64) -> std::result::Result<(), ()> {
+        self.send.send(CameraCommand::Warm(rate)).map_err(|_| ())
+    }
+
+    pub fn record(&self, path: PathBuf, limit: RecordLimit) -> std::result::Result<(), ()> {
+        self.send
+            .send(CameraCommand::Record(path, limit))
+            .map_err(|_| ())
+    }
+
+    pub fn stop_recording(&self) -> std::result::Result<(), ()> {
+        self.send.send(CameraCommand::StopRecording).map_err(|_| ())
+    }
+
     pub fn user_update(&mut self, user_update: UserUpdate) {
-        if let UserUpdate::CameraUpdate(data) = user_update {
+        if let UserUpdate::CameraUpdate(_, data) = user_update {
             self.data = data;
         }
     }
 }
 
-fn run(recv: mpsc::Receiver<CameraCommand>, send: SendUserUpdate) -> Result<()> {
-    let mut camera = Some(camera::interface::autoconnect(false)?);
+fn frame_metadata(
+    camera: &dyn CameraDriver,
+    controls: &[ControlValue],
+    mount: &Option<MountData>,
+) -> FrameMetadata {
+    let get = |id: ControlId| controls.iter().find(|c| c.id == id).map(|c| c.value);
+    FrameMetadata {
+        start: None,
+        exposure: get(ControlId::ControlExposure)
+            .filter(|&exposure| exposure >= 0.0 && exposure.is_finite())
+            .map(|exposure| Duration::from_secs_f64(exposure / EXPOSURE_FACTOR)),
+        gain: get(ControlId::ControlGain),
+        offset: get(ControlId::ControlOffset),
+        temperature: get(ControlId::ControlCurtemp),
+        bin: camera.bin(),
+        camera: camera.name().to_string(),
+        sequence: 0,
+        ra_dec: mount.as_ref().map(|mount| mount.ra_dec_real),
+        az_alt: mount.as_ref().map(|mount| mount.az_alt),
+        location: mount.as_ref().map(|mount| mount.location),
+    }
+}
+
+fn run(
+    recv: mpsc::Receiver<CameraCommand>,
+    send: SendUserUpdate,
+    id: CameraId,
+    role: CameraRole,
+    connect: impl FnOnce() -> Result<Box<dyn CameraDriver>>,
+) -> Result<()> {
+    let mut cmd_status = String::new();
+    let mut camera = match connect() {
+        Ok(camera) => Some(camera),
+        Err(err) => {
+            cmd_status = format!("{} (select or simulate a camera)", err);
+            None
+        }
+    };
+    let mut available = Vec::new();
+    let mut cooler = Cooler::new();
+    let mut recorder = None;
+    let mut mount = None;
+    let mut sequence = 0;
     let mut running = false;
     let mut exposure_duration = Duration::default();
-    let mut cmd_status = String::new();
     let mut exposure_start = Instant::now();
     loop {
         let mut should_restart = false;
@@ -94,13 +246,28 @@ fn run(recv: mpsc::Receiver<CameraCommand>, send: SendUserUpdate) -> Result<()>
         let mut had_bad_cmd = false;
         loop {
             match recv.try_recv() {
-                Ok(cmd) => match run_one(&mut camera, cmd, &mut running, &mut should_restart) {
-                    Ok(()) => had_cmd = true,
-                    Err(err) => {
-                        had_bad_cmd = true;
-                        cmd_status = format!("{}", err);
+                Ok(cmd) => {
+                    // mount updates arrive constantly without the user doing anything, so they
+                    // mustn't clear the last command's error
+                    let user_cmd = !matches!(cmd, CameraCommand::SetMountData(_));
+                    match run_one(
+                        &mut camera,
+                        cmd,
+                        role,
+                        &mut available,
+                        &mut cooler,
+                        &mut recorder,
+                        &mut mount,
+                        &mut running,
+                        &mut should_restart,
+                    ) {
+                        Ok(()) => had_cmd |= user_cmd,
+                        Err(err) => {
+                            had_bad_cmd = true;
+                            cmd_status = format!("{}", err);
+                        }
                     }
-                },
+                }
                 Err(mpsc::TryRecvError::Empty) => break,
                 Err(mpsc::TryRecvError::Disconnected) => return Ok(()),
             }
@@ -109,9 +276,32 @@ fn run(recv: mpsc::Receiver<CameraCommand>, send: SendUserUpdate) -> Result<()>
             cmd_status.clear();
         }
 
-        let camera = camera.as_mut().unwrap();
+        let camera = match camera {
+            Some(ref mut camera) => camera,
+            None => {
+                // nothing to run until a camera is selected or simulated
+                let data = CameraData {
+                    available: available.clone(),
+                    cmd_status: cmd_status.clone(),
+                    ..CameraData::new(role)
+                };
+                if send.send_event(UserUpdate::CameraUpdate(id, data)).is_err() {
+                    return Ok(());
+                }
+                std::thread::sleep(Duration::from_millis(100));
+                continue;
+            }
+        };
 
-        let values = camera.controls().iter().map(|c| c.to_value()).collect();
+        if let Err(err) = cooler.step(camera.as_mut()) {
+            cmd_status = format!("{}", err);
+        }
+        if let Some(ref mut recorder) = recorder {
+            recorder.check_limit();
+        }
+
+        let values = camera.control_values();
+        let mut meta = frame_metadata(camera.as_ref(), &values, &mount);
 
         if should_restart {
             camera.start()?;
@@ -120,15 +310,21 @@ fn run(recv: mpsc::Receiver<CameraCommand>, send: SendUserUpdate) -> Result<()>
         let data = CameraData {
             controls: values,
             name: camera.name().to_string(),
+            role,
+            available: available.clone(),
             cmd_status: cmd_status.clone(),
             running,
             is_live: camera.use_live(),
+            bin: camera.bin(),
+            bits: camera.bits(),
+            cooler: cooler.status(),
+            recording: recorder.as_ref().map(Recorder::status),
             exposure_start,
             exposure_duration,
             effective_area: Some(camera.effective_area()),
         };
 
-        match send.send_event(UserUpdate::CameraUpdate(data)) {
+        match send.send_event(UserUpdate::CameraUpdate(id, data)) {
             Ok(()) => (),
             Err(_) => return Ok(()),
         }
@@ -136,11 +332,23 @@ fn run(recv: mpsc::Receiver<CameraCommand>, send: SendUserUpdate) -> Result<()>
             if camera.use_live() {
                 loop {
                     match camera.get_live() {
-                        Some(frame) => {
-                            send.send_event(UserUpdate::CameraData(Arc::new(frame)))?;
+                        Some(mut frame) => {
+                            let now = time::OffsetDateTime::now_utc();
+                            meta.start = Some(now - meta.exposure.unwrap_or_default());
+                            meta.sequence = sequence;
+                            frame.meta = meta;
+                            sequence += 1;
+                            let frame = Arc::new(frame);
+                            if let Some(ref mut recorder) = recorder {
+                                recorder.record(&frame);
+                            }
+                            send.send_event(UserUpdate::CameraData(id, frame))?;
                             break;
                         }
                         None => {
+                            if let Err(err) = cooler.step(camera.as_mut()) {
+                                cmd_status = format!("{}", err);
+                            }
                             let limit = Duration::from_millis(10);
                             if exposure_duration > limit || Instant::now() - exposure_start > limit
                             {
@@ -150,9 +358,27 @@ fn run(recv: mpsc::Receiver<CameraCommand>, send: SendUserUpdate) -> Result<()>
                     }
                 }
             } else {
+                meta.start = Some(time::OffsetDateTime::now_utc());
                 camera.start_single()?;
-                let single = camera.get_single()?;
-                send.send_event(UserUpdate::CameraData(Arc::new(single)))?;
+                // get_single blocks until the frame is read out, so wait out the exposure here
+                // instead, keeping the cooler ramping through long exposures
+                let exposure_end = Instant::now() + meta.exposure.unwrap_or_default();
+                while Instant::now() < exposure_end {
+                    if let Err(err) = cooler.step(camera.as_mut()) {
+                        cmd_status = format!("{}", err);
+                    }
+                    let remaining = exposure_end.saturating_duration_since(Instant::now());
+                    std::thread::sleep(remaining.min(Duration::from_millis(100)));
+                }
+                let mut single = camera.get_single()?;
+                meta.sequence = sequence;
+                single.meta = meta;
+                sequence += 1;
+                let single = Arc::new(single);
+                if let Some(ref mut recorder) = recorder {
+                    recorder.record(&single);
+                }
+                send.send_event(UserUpdate::CameraData(id, single))?;
             }
             let new_exposure_start = Instant::now();
             exposure_duration = new_exposure_start - exposure_start;
@@ -163,48 +389,50 @@ fn run(recv: mpsc::Receiver<CameraCommand>, send: SendUserUpdate) -> Result<()>
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn run_one(
-    camera: &mut Option<camera::interface::Camera>,
+    camera: &mut Option<Box<dyn CameraDriver>>,
     cmd: CameraCommand,
+    role: CameraRole,
+    available: &mut Vec<String>,
+    cooler: &mut Cooler,
+    recorder: &mut Option<Recorder>,
+    mount: &mut Option<MountData>,
     running: &mut bool,
     restart: &mut bool,
 ) -> Result<()> {
     match cmd {
         CameraCommand::SetControl(id, val) => {
-            let camera = camera.as_mut().unwrap();
-            cancel_for_modification(camera, running, restart)?;
-            for control in camera.controls() {
-                if control.id() == id {
-                    control.set(val)?;
-                }
-            }
+            let camera = camera.as_mut().ok_or("No camera connected")?;
+            cancel_for_modification(camera.as_mut(), running, restart)?;
+            camera.set_control(id, val)?;
         }
         CameraCommand::Start => {
             if !*running {
+                camera.as_mut().ok_or("No camera connected")?.start()?;
                 *running = true;
-                camera.as_mut().unwrap().start()?;
             }
         }
         CameraCommand::Stop => {
             if *running {
                 *running = false;
                 *restart = false;
-                camera.as_mut().unwrap().stop()?;
+                if let Some(ref mut camera) = camera {
+                    camera.stop()?;
+                }
             }
         }
         CameraCommand::ToggleLive => {
-            if let Some(ref camera) = camera {
+            if let Some(ref mut camera) = camera {
                 if *running {
                     camera.stop()?;
                 }
             }
-            let info = camera
-                .take()
-                .map(|camera| (camera.info().clone(), camera.use_live()));
-            if let Some((info, old_use_live)) = info {
-                *camera = Some(info.open(!old_use_live)?);
+            if let Some(old) = camera.take() {
+                let use_live = !old.use_live();
+                *camera = Some(old.reopen(use_live)?);
             }
-            if let Some(ref camera) = camera {
+            if let Some(ref mut camera) = camera {
                 if *running {
                     camera.start()?;
                 }
@@ -212,19 +440,129 @@ fn run_one(
         }
         CameraCommand::SetROI(roi) => {
             if let Some(ref mut camera) = camera {
-                cancel_for_modification(camera, running, restart)?;
+                cancel_for_modification(camera.as_mut(), running, restart)?;
                 match roi {
                     Some(roi) => camera.set_roi(roi)?,
                     None => camera.unset_roi()?,
                 }
             }
         }
+        CameraCommand::SetBin(bin) => {
+            if let Some(ref mut camera) = camera {
+                cancel_for_modification(camera.as_mut(), running, restart)?;
+                camera.set_bin(bin)?;
+            }
+        }
+        CameraCommand::SetBits(bits) => {
+            if let Some(ref mut camera) = camera {
+                cancel_for_modification(camera.as_mut(), running, restart)?;
+                camera.set_bits(bits)?;
+            }
+        }
+        CameraCommand::SetMountData(data) => {
+            if let Some(ref mut camera) = camera {
+                camera.set_mount_data(&data);
+            }
+            *mount = Some(data);
+        }
+        CameraCommand::Simulate(psf) => {
+            let mut use_live = false;
+            if let Some(mut old) = camera.take() {
+                if *running {
+                    old.stop()?;
+                }
+                use_live = old.use_live();
+            }
+            let mut simulated = SimulatedCamera::new(use_live, psf);
+            if *running {
+                simulated.start()?;
+            }
+            *camera = Some(Box::new(simulated));
+        }
+        CameraCommand::Cool(target, rate) => {
+            if let Some(ref camera) = camera {
+                cooler.cool(camera.as_ref(), target, rate)?;
+            }
+        }
+        CameraCommand::Warm(rate) => {
+            if let Some(ref mut camera) = camera {
+                cooler.warm(camera.as_mut(), rate)?;
+            }
+        }
+        CameraCommand::Record(path, limit) => {
+            if recorder.as_ref().map_or(false, |recorder| !recorder.done()) {
+                return Err("Already recording".into());
+            }
+            let name = camera
+                .as_ref()
+                .map_or("", |camera| camera.name())
+                .to_string();
+            *recorder = Some(Recorder::new(path, limit, name));
+        }
+        CameraCommand::StopRecording => {
+            if let Some(ref mut recorder) = recorder {
+                recorder.stop();
+            }
+        }
+        CameraCommand::List => {
+            *available = camera::interface::list_cameras()?
+                .into_iter()
+                .map(|info| info.name().to_string())
+                .collect();
+            available.push(SIMULATOR_NAME.to_string());
+        }
+        CameraCommand::Select(selector) => {
+            let selector = match selector.parse::<usize>() {
+                Ok(index) if index < available.len() => available[index].clone(),
+                _ => selector,
+            };
+            let use_live = camera.as_ref().map_or(false, |old| old.use_live());
+            let info = if selector.eq_ignore_ascii_case(SIMULATOR_NAME) {
+                None
+            } else {
+                Some(camera::interface::find_camera(&selector)?)
+            };
+            if let (Some(info), Some(old)) = (&info, camera.as_ref()) {
+                if info.name() == old.name() {
+                    role.set_default_camera(info.name())?;
+                    return Ok(());
+                }
+            }
+            if *running {
+                if let Some(ref mut old) = camera {
+                    old.stop()?;
+                }
+            }
+            let new: Result<Box<dyn CameraDriver>> = match info {
+                Some(info) => info
+                    .open(use_live)
+                    .map(|new| Box::new(new) as Box<dyn CameraDriver>),
+                None => Ok(Box::new(SimulatedCamera::new(use_live, Psf::default()))),
+            };
+            let mut new = match new {
+                Ok(new) => new,
+                Err(err) => {
+                    // keep using the old camera if the new one can't be opened
+                    if *running {
+                        if let Some(ref mut old) = camera {
+                            old.start()?;
+                        }
+                    }
+                    return Err(err);
+                }
+            };
+            role.set_default_camera(new.name())?;
+            if *running {
+                new.start()?;
+            }
+            *camera = Some(new);
+        }
     }
     Ok(())
 }
 
 fn cancel_for_modification(
-    camera: &camera::interface::Camera,
+    camera: &mut dyn CameraDriver,
     running: &mut bool,
     restart: &mut bool,
 ) -> Result<()> {
@@ -0,0 +1,51 @@
+use crate::Result;
+use std::{
+    collections::BTreeMap,
+    fs::{create_dir_all, read_to_string, write},
+    path::PathBuf,
+};
+
+// persistent settings, stored as key=value lines
+fn config_path() -> Option<PathBuf> {
+    let mut path = dirs::config_dir()?;
+    path.push("scopie");
+    path.push("config.txt");
+    Some(path)
+}
+
+fn load() -> BTreeMap<String, String> {
+    let mut result = BTreeMap::new();
+    let contents = match config_path().and_then(|path| read_to_string(path).ok()) {
+        Some(contents) => contents,
+        None => return result,
+    };
+    for line in contents.lines() {
+        if let Some(index) = line.find('=') {
+            let (key, value) = line.split_at(index);
+            result.insert(key.trim().to_string(), value[1..].trim().to_string());
+        }
+    }
+    result
+}
+
+pub fn get(key: &str) -> Option<String> {
+    load().remove(key)
+}
+
+pub fn set(key: &str, value: &str) -> Result<()> {
+    let path = config_path().ok_or("Unable to find config directory")?;
+    let mut values = load();
+    values.insert(key.to_string(), value.to_string());
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)?;
+    }
+    let mut contents = String::new();
+    for (key, value) in values {
+        contents.push_str(&key);
+        contents.push('=');
+        contents.push_str(&value);
+        contents.push('\n');
+    }
+    write(path, contents)?;
+    Ok(())
+}
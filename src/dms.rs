@@ -33,6 +33,16 @@ impl Angle {
         self.value * 360.0
     }
 
+    // -180 to 180, for declinations and latitudes
+    pub fn signed_degrees(self) -> f64 {
+        let degrees = self.degrees();
+        if degrees > 180.0 {
+            degrees - 360.0
+        } else {
+            degrees
+        }
+    }
+
     pub fn from_hours(hours: f64) -> Self {
         Self::from_0to1(hours / 24.0)
     }
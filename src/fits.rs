@@ -0,0 +1,416 @@
+use crate::{
+    camera::interface::{FrameMetadata, ROIImage},
+    dms::Angle,
+    Result,
+};
+use khygl::texture::CpuTexture;
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    fmt,
+    fs::{read, File},
+    io::{BufWriter, Write},
+    path::Path,
+    str::FromStr,
+    time::Duration,
+};
+
+// FITS files are made of 2880 byte blocks, headers of 80 character cards
+const BLOCK_SIZE: usize = 2880;
+const CARD_SIZE: usize = 80;
+// characters that fit between the quotes of a string value, which starts in column 11
+const MAX_STRING: usize = CARD_SIZE - 12;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageType {
+    Light,
+    Dark,
+    Flat,
+    Bias,
+}
+
+impl fmt::Display for ImageType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // the spellings most stacking programs look for
+        match self {
+            ImageType::Light => write!(f, "Light Frame"),
+            ImageType::Dark => write!(f, "Dark Frame"),
+            ImageType::Flat => write!(f, "Flat Field"),
+            ImageType::Bias => write!(f, "Bias Frame"),
+        }
+    }
+}
+
+impl ImageType {
+    // the name used in commands and filenames
+    pub fn short_name(self) -> &'static str {
+        match self {
+            ImageType::Light => "light",
+            ImageType::Dark => "dark",
+            ImageType::Flat => "flat",
+            ImageType::Bias => "bias",
+        }
+    }
+}
+
+impl FromStr for ImageType {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "light" => Ok(ImageType::Light),
+            "dark" => Ok(ImageType::Dark),
+            "flat" => Ok(ImageType::Flat),
+            "bias" => Ok(ImageType::Bias),
+            _ => Err(format!("Unknown frame type: {}", s)),
+        }
+    }
+}
+
+/// What was being observed, which the camera itself doesn't know about.
+#[derive(Clone, Debug)]
+pub struct Observation {
+    pub object: String,
+    pub telescope: String,
+    pub filter: String,
+    pub image_type: ImageType,
+}
+
+struct Header {
+    data: Vec<u8>,
+}
+
+impl Header {
+    fn new() -> Self {
+        Self { data: Vec::new() }
+    }
+
+    fn card(&mut self, text: &str) {
+        let mut bytes = text.as_bytes().to_vec();
+        bytes.retain(|&b| (b' '..=b'~').contains(&b));
+        bytes.resize(CARD_SIZE, b' ');
+        self.data.extend_from_slice(&bytes);
+    }
+
+    // fixed format: keyword in columns 1-8, value right justified to column 30
+    fn value(&mut self, key: &str, value: &str, comment: &str) {
+        self.card(&format!("{:<8}= {:>20} / {}", key, value, comment));
+    }
+
+    fn logical(&mut self, key: &str, value: bool, comment: &str) {
+        self.value(key, if value { "T" } else { "F" }, comment);
+    }
+
+    fn int(&mut self, key: &str, value: i64, comment: &str) {
+        self.value(key, &value.to_string(), comment);
+    }
+
+    fn float(&mut self, key: &str, value: f64, comment: &str) {
+        if !value.is_finite() {
+            return;
+        }
+        let mut text = value.to_string();
+        if !text.contains('.') {
+            text.push_str(".0");
+        }
+        // very large or small values print with every digit, which doesn't fit the 20 columns
+        // of a fixed format value, so those use an exponent instead
+        if text.len() > 20 {
+            text = format!("{:.10E}", value);
+        }
+        self.value(key, &text, comment);
+    }
+
+    fn string(&mut self, key: &str, value: &str, comment: &str) {
+        // strings start in column 11 and are padded to at least 8 characters. Long values are
+        // cut short before quoting, so the closing quote isn't lost off the end of the card.
+        let mut escaped = String::new();
+        for c in value.chars().filter(|&c| (' '..='~').contains(&c)) {
+            let piece = if c == '\'' {
+                "''".to_string()
+            } else {
+                c.to_string()
+            };
+            if escaped.len() + piece.len() > MAX_STRING {
+                break;
+            }
+            escaped.push_str(&piece);
+        }
+        let quoted = format!("'{:<8}'", escaped);
+        self.card(&format!("{:<8}= {:<20} / {}", key, quoted, comment));
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.card("END");
+        pad(&mut self.data, b' ');
+        self.data
+    }
+}
+
+fn pad(data: &mut Vec<u8>, value: u8) {
+    let len = (data.len() + BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE;
+    data.resize(len, value);
+}
+
+// float data is written as 32 bit IEEE floats, otherwise as offset 16 bit integers
+fn header(
+    meta: &FrameMetadata,
+    size: (usize, usize),
+    float: bool,
+    observation: &Observation,
+) -> Vec<u8> {
+    let mut header = Header::new();
+    header.logical("SIMPLE", true, "conforms to FITS standard");
+    if float {
+        header.int("BITPIX", -32, "32 bit floats");
+    } else {
+        header.int("BITPIX", 16, "16 bit signed integers");
+    }
+    header.int("NAXIS", 2, "number of axes");
+    header.int("NAXIS1", size.0 as i64, "width");
+    header.int("NAXIS2", size.1 as i64, "height");
+    if !float {
+        // FITS has no unsigned integers, so data is stored offset by 32768
+        header.int("BZERO", 32768, "offset for unsigned data");
+        header.int("BSCALE", 1, "default scaling");
+    }
+    if let Some(start) = meta.start {
+        let date = format!(
+            "{}.{:03}",
+            start.format("%Y-%m-%dT%H:%M:%S"),
+            start.millisecond()
+        );
+        header.string("DATE-OBS", &date, "UTC start of exposure");
+    }
+    if let Some(exposure) = meta.exposure {
+        header.float(
+            "EXPTIME",
+            exposure.as_secs_f64(),
+            "exposure time in seconds",
+        );
+    }
+    if let Some(gain) = meta.gain {
+        header.float("GAIN", gain, "sensor gain");
+    }
+    if let Some(offset) = meta.offset {
+        header.float("OFFSET", offset, "sensor offset");
+    }
+    if let Some(temperature) = meta.temperature {
+        header.float("CCD-TEMP", temperature, "sensor temperature in C");
+    }
+    header.int("XBINNING", meta.bin as i64, "binning factor in x");
+    header.int("YBINNING", meta.bin as i64, "binning factor in y");
+    if !meta.camera.is_empty() {
+        header.string("INSTRUME", &meta.camera, "camera");
+    }
+    if !observation.telescope.is_empty() {
+        header.string("TELESCOP", &observation.telescope, "telescope");
+    }
+    if let Some((ra, dec)) = meta.ra_dec {
+        header.float("RA", ra.degrees(), "telescope right ascension in degrees");
+        header.float(
+            "DEC",
+            dec.signed_degrees(),
+            "telescope declination in degrees",
+        );
+    }
+    if !observation.object.is_empty() {
+        header.string("OBJECT", &observation.object, "target");
+    }
+    if !observation.filter.is_empty() {
+        header.string("FILTER", &observation.filter, "filter");
+    }
+    if let Some((lat, lon)) = meta.location {
+        header.float("SITELAT", lat.signed_degrees(), "site latitude in degrees");
+        header.float(
+            "SITELONG",
+            lon.signed_degrees(),
+            "site longitude in degrees",
+        );
+    }
+    header.string(
+        "IMAGETYP",
+        &observation.image_type.to_string(),
+        "frame type",
+    );
+    header.finish()
+}
+
+pub fn write_fits(path: impl AsRef<Path>, img: &ROIImage, observation: &Observation) -> Result<()> {
+    let mut data = header(&img.meta, img.image.size, false, observation);
+    for &value in img.image.data() {
+        // big endian, shifted into i16 range (undone by BZERO)
+        data.extend_from_slice(&(value ^ 0x8000).to_be_bytes());
+    }
+    pad(&mut data, 0);
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(&data)?;
+    file.flush()?;
+    Ok(())
+}
+
+/// Writes float data, e.g. a stack whose precision would be lost by rounding to u16.
+pub fn write_fits_f32(
+    path: impl AsRef<Path>,
+    pixels: &[f32],
+    size: (usize, usize),
+    meta: &FrameMetadata,
+    observation: &Observation,
+) -> Result<()> {
+    if pixels.len() != size.0 * size.1 {
+        return Err("Pixel count doesn't match the image size".into());
+    }
+    let mut data = header(meta, size, true, observation);
+    for &value in pixels {
+        data.extend_from_slice(&value.to_be_bytes());
+    }
+    pad(&mut data, 0);
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(&data)?;
+    file.flush()?;
+    Ok(())
+}
+
+// keyword -> value, with strings unquoted
+fn parse_header(data: &[u8]) -> Result<(HashMap<String, String>, usize)> {
+    let mut keywords = HashMap::new();
+    for (index, card) in data.chunks_exact(CARD_SIZE).enumerate() {
+        // headers are ASCII, so this keeps every character one byte long
+        let card = card
+            .iter()
+            .map(|&b| if b.is_ascii() { b as char } else { ' ' })
+            .collect::<String>();
+        let key = card[..8].trim();
+        if key == "END" {
+            let header_len = (index + 1) * CARD_SIZE;
+            let header_len = (header_len + BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE;
+            return Ok((keywords, header_len));
+        }
+        if &card[8..10] != "= " {
+            continue;
+        }
+        let value = card[10..].trim_start();
+        let value = if value.starts_with('\'') {
+            // a doubled quote is an escaped quote
+            let mut result = String::new();
+            let mut chars = value[1..].chars().peekable();
+            while let Some(c) = chars.next() {
+                if c == '\'' {
+                    if chars.peek() == Some(&'\'') {
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                result.push(c);
+            }
+            result.trim_end().to_string()
+        } else {
+            value.split('/').next().unwrap_or("").trim().to_string()
+        };
+        keywords.insert(key.to_string(), value);
+    }
+    Err("FITS header has no END".into())
+}
+
+fn metadata(keywords: &HashMap<String, String>) -> FrameMetadata {
+    let float = |key: &str| keywords.get(key).and_then(|v| v.parse::<f64>().ok());
+    let angles = |a: &str, b: &str| match (float(a), float(b)) {
+        (Some(a), Some(b)) => Some((Angle::from_degrees(a), Angle::from_degrees(b))),
+        _ => None,
+    };
+    FrameMetadata {
+        exposure: float("EXPTIME")
+            .filter(|&exposure| exposure >= 0.0 && exposure.is_finite())
+            .map(Duration::from_secs_f64),
+        gain: float("GAIN"),
+        offset: float("OFFSET"),
+        temperature: float("CCD-TEMP"),
+        bin: float("XBINNING").map_or(1, |bin| (bin as usize).max(1)),
+        camera: keywords.get("INSTRUME").cloned().unwrap_or_default(),
+        ra_dec: angles("RA", "DEC"),
+        location: angles("SITELAT", "SITELONG"),
+        ..FrameMetadata::default()
+    }
+}
+
+/// Reads the primary image of a FITS file, converted to 16 bit. Only the first plane of a
+/// data cube is read.
+pub fn read_fits(path: impl AsRef<Path>) -> Result<ROIImage> {
+    let data = read(path)?;
+    let (keywords, header_len) = parse_header(&data)?;
+    let int = |key: &str| -> Result<i64> {
+        Ok(keywords
+            .get(key)
+            .ok_or_else(|| format!("FITS header missing {}", key))?
+            .parse::<i64>()?)
+    };
+    let float = |key: &str, default: f64| {
+        keywords
+            .get(key)
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(default)
+    };
+    let bitpix = int("BITPIX")?;
+    if ![8, 16, 32, -32, -64].contains(&bitpix) {
+        return Err(format!("Unsupported FITS BITPIX {}", bitpix).into());
+    }
+    let naxis = int("NAXIS")?;
+    if naxis < 2 {
+        return Err(format!("FITS image has {} axes, need 2", naxis).into());
+    }
+    let width: usize = int("NAXIS1")?.try_into()?;
+    let height: usize = int("NAXIS2")?.try_into()?;
+    let bzero = float("BZERO", 0.0);
+    let bscale = float("BSCALE", 1.0);
+    let bytes = (bitpix.abs() / 8) as usize;
+    let raw = width
+        .checked_mul(height)
+        .and_then(|count| count.checked_mul(bytes))
+        .and_then(|len| data.get(header_len..header_len.checked_add(len)?))
+        .ok_or("FITS file is truncated")?;
+    // FITS data is big endian
+    let physical = raw
+        .chunks_exact(bytes)
+        .map(|b| {
+            let value = match bitpix {
+                8 => f64::from(b[0]),
+                16 => f64::from(i16::from_be_bytes([b[0], b[1]])),
+                32 => f64::from(i32::from_be_bytes([b[0], b[1], b[2], b[3]])),
+                -32 => f64::from(f32::from_be_bytes([b[0], b[1], b[2], b[3]])),
+                _ => f64::from_be_bytes(b.try_into().expect("chunks_exact")),
+            };
+            bzero + bscale * value
+        })
+        .collect::<Vec<_>>();
+    let (min, max) = physical
+        .iter()
+        .filter(|v| v.is_finite())
+        .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), &v| {
+            (min.min(v), max.max(v))
+        });
+    // pick a mapping into 0-65535 that keeps the data's meaning where possible
+    let (scale, offset) = if bitpix == 8 && min >= 0.0 && max <= 255.0 {
+        (257.0, 0.0)
+    } else if bitpix < 0 && min >= 0.0 && max <= 1.0 {
+        (65535.0, 0.0)
+    } else if min >= 0.0 && max <= 65535.0 {
+        (1.0, 0.0)
+    } else if max > min {
+        (65535.0 / (max - min), -min)
+    } else {
+        (1.0, -min)
+    };
+    let pixels = physical
+        .iter()
+        .map(|&v| {
+            if v.is_finite() {
+                ((v + offset) * scale).round().max(0.0).min(65535.0) as u16
+            } else {
+                0
+            }
+        })
+        .collect();
+    let mut image: ROIImage = CpuTexture::new(pixels, (width, height)).into();
+    image.meta = metadata(&keywords);
+    Ok(image)
+}
@@ -1,14 +1,41 @@
-use crate::{camera::interface::ROIImage, Result};
-use khygl::{render_texture::TextureRenderer, texture::Texture, Rect};
-use std::sync::Arc;
+use crate::{
+    alg::{colormap::Colormap, starfinder::Star},
+    camera::interface::ROIImage,
+    Result,
+};
+use khygl::{
+    render_text::TextRenderer,
+    render_texture::TextureRenderer,
+    texture::{CpuTexture, Texture},
+    Rect,
+};
+use std::{f64::consts::PI, sync::Arc};
+
+// marker radius, in multiples of the star's HFR
+const MARKER_HFR_SCALE: f64 = 3.0;
+const MARKER_MIN_RADIUS: f64 = 4.0;
+const MARKER_POINTS: usize = 24;
+// only the brightest stars get markers and labels, to keep the image readable
+const MAX_MARKERS: usize = 500;
+const MAX_LABELS: usize = 20;
 
 pub struct ImageDisplay {
     raw: Option<Arc<ROIImage>>,
     texture: Option<Texture<u16>>,
+    // used instead of texture when a colormap is selected, with the whole stretch baked in
+    color_texture: Option<Texture<[u8; 4]>>,
+    // non-linear stretch applied on upload, since the renderer only does scale/offset
+    curve: Option<Arc<Vec<u16>>>,
+    colormap: Colormap,
+    // lookup table of the current colormap, from stretched value to color
+    color_table: Vec<[u8; 4]>,
+    // the textures need to be uploaded again before the next draw
+    dirty: bool,
     displayer: TextureRenderer,
-    pub scale_offset: (f32, f32),
+    scale_offset: (f32, f32),
     pub cross: bool,
     pub bin: bool,
+    pub stars: bool,
 }
 
 pub struct Mapping {
@@ -22,11 +49,17 @@ impl ImageDisplay {
         Self {
             raw: None,
             texture: None,
+            color_texture: None,
+            curve: None,
+            colormap: Colormap::Grey,
+            color_table: Vec::new(),
+            dirty: false,
             displayer: TextureRenderer::new_binning()
                 .expect("failed to build binning texture renderer"),
             scale_offset: (1.0, 0.0),
             cross: false,
             bin: true,
+            stars: true,
         }
     }
 
@@ -46,8 +79,162 @@ impl ImageDisplay {
                 tex
             });
         }
-        self.texture.as_mut().unwrap().upload(&raw.image)?;
         self.raw = Some(raw);
+        self.dirty = true;
+        Ok(())
+    }
+
+    pub fn set_scale_offset(&mut self, scale_offset: (f32, f32)) {
+        // the grey texture is stretched by the renderer, colormapped ones on upload
+        if scale_offset != self.scale_offset && self.colormap != Colormap::Grey {
+            self.dirty = true;
+        }
+        self.scale_offset = scale_offset;
+    }
+
+    pub fn colormap(&self) -> Colormap {
+        self.colormap
+    }
+
+    pub fn set_colormap(&mut self, colormap: Colormap) {
+        if colormap != self.colormap {
+            self.colormap = colormap;
+            self.color_table = if colormap == Colormap::Grey {
+                Vec::new()
+            } else {
+                colormap.lookup_table()
+            };
+            self.dirty = true;
+        }
+    }
+
+    pub fn set_curve(&mut self, curve: Option<Arc<Vec<u16>>>) -> Result<()> {
+        let changed = match (&self.curve, &curve) {
+            (Some(old), Some(new)) => !Arc::ptr_eq(old, new),
+            (None, None) => false,
+            _ => true,
+        };
+        if changed {
+            self.curve = curve;
+            self.dirty = true;
+        }
+        Ok(())
+    }
+
+    fn upload(&mut self) -> Result<()> {
+        let raw = match self.raw {
+            Some(ref raw) => raw,
+            None => return Ok(()),
+        };
+        if self.colormap != Colormap::Grey {
+            let curve = self.curve.as_ref();
+            let (scale, offset) = self.scale_offset;
+            let scale = scale / f32::from(u16::max_value());
+            let table = &self.color_table;
+            let colored = raw
+                .image
+                .data()
+                .iter()
+                .map(|&value| {
+                    let value = curve.map_or(value, |curve| curve[usize::from(value)]);
+                    table[Colormap::table_index(f32::from(value) * scale + offset)]
+                })
+                .collect();
+            let create = self
+                .color_texture
+                .as_ref()
+                .map_or(true, |texture| texture.size != raw.image.size);
+            if create {
+                self.color_texture = Some(Texture::new(raw.image.size)?);
+            }
+            self.color_texture
+                .as_mut()
+                .unwrap()
+                .upload(&CpuTexture::new(colored, raw.image.size))?;
+        } else if let Some(texture) = self.texture.as_mut() {
+            match self.curve {
+                Some(ref curve) => {
+                    let stretched = raw
+                        .image
+                        .data()
+                        .iter()
+                        .map(|&value| curve[usize::from(value)])
+                        .collect();
+                    texture.upload(&CpuTexture::new(stretched, raw.image.size))?;
+                }
+                None => texture.upload(&raw.image)?,
+            }
+        }
+        Ok(())
+    }
+
+    // draws a circle around each star, sized by HFR, with the HFR of the brightest written next
+    // to them. src and mapping are what draw returned.
+    pub fn draw_stars(
+        &self,
+        stars: &[Star],
+        src: &Rect<usize>,
+        mapping: &Mapping,
+        displayer: &TextureRenderer,
+        text_renderer: &mut TextRenderer,
+        screen_size: (f32, f32),
+    ) -> Result<()> {
+        if !self.stars {
+            return Ok(());
+        }
+        let mut visible = stars
+            .iter()
+            .filter(|star| {
+                star.x >= src.x as f64
+                    && star.y >= src.y as f64
+                    && star.x < src.right() as f64
+                    && star.y < src.bottom() as f64
+            })
+            .collect::<Vec<_>>();
+        visible.sort_by(|l, r| r.flux.total_cmp(&l.flux));
+        visible.truncate(MAX_MARKERS);
+        let to_screen = |x: f64, y: f64| {
+            (
+                x * mapping.scale.0 + mapping.offset.0,
+                y * mapping.scale.1 + mapping.offset.1,
+            )
+        };
+        let screen_usize = (screen_size.0 as usize, screen_size.1 as usize);
+        for (index, star) in visible.iter().enumerate() {
+            let (x, y) = to_screen(star.x + 0.5, star.y + 0.5);
+            let radius = (star.hfr * MARKER_HFR_SCALE * mapping.scale.0).max(MARKER_MIN_RADIUS);
+            let color = if star.saturated {
+                [1.0, 0.3, 0.3, 1.0]
+            } else {
+                [0.3, 1.0, 0.3, 1.0]
+            };
+            for point in 0..MARKER_POINTS {
+                let angle = point as f64 * 2.0 * PI / MARKER_POINTS as f64;
+                let point_x = x + radius * angle.cos();
+                let point_y = y + radius * angle.sin();
+                if point_x < 0.0
+                    || point_y < 0.0
+                    || point_x >= f64::from(screen_size.0)
+                    || point_y >= f64::from(screen_size.1)
+                {
+                    continue;
+                }
+                displayer.rect(
+                    Rect::new(point_x as usize, point_y as usize, 2, 2),
+                    color,
+                    screen_size,
+                )?;
+            }
+            if index < MAX_LABELS && x + radius >= 0.0 && y >= 0.0 {
+                text_renderer.render(
+                    displayer,
+                    &format!("{:.1}", star.hfr),
+                    color,
+                    ((x + radius) as usize + 2, y as usize),
+                    screen_usize,
+                )?;
+            }
+        }
         Ok(())
     }
 
@@ -80,6 +267,10 @@ impl ImageDisplay {
         screen_size: (f32, f32),
         roi: &crate::camera::display::ROIThing,
     ) -> Result<(Rect<usize>, Mapping)> {
+        if self.dirty {
+            self.upload()?;
+            self.dirty = false;
+        }
         if let (Some(texture), Some(raw)) = (self.texture.as_ref(), self.raw.as_ref()) {
             let roi_unclamped = roi.get_roi_unclamped(&raw.original);
             let roi_clamped =
@@ -126,11 +317,19 @@ impl ImageDisplay {
             );
 
             let disp = if self.bin { &self.displayer } else { displayer };
-            disp.render(texture, screen_size)
-                .src(src.to_f32())
-                .dst(dst.to_f32())
-                .scale_offset(self.scale_offset)
-                .go()?;
+            match self.color_texture {
+                Some(ref color_texture) if self.colormap != Colormap::Grey => disp
+                    .render(color_texture, screen_size)
+                    .src(src.to_f32())
+                    .dst(dst.to_f32())
+                    .go()?,
+                _ => disp
+                    .render(texture, screen_size)
+                    .src(src.to_f32())
+                    .dst(dst.to_f32())
+                    .scale_offset(self.scale_offset)
+                    .go()?,
+            }
             if self.cross {
                 let half_x = dst.x + (dst.width / 2.0);
                 let half_y = dst.y + (dst.height / 2.0);
@@ -172,6 +371,42 @@ impl ImageDisplay {
     }
 }
 
+// heights and the black/white points are all 0 to 1 across the rect
+pub fn draw_histogram(
+    pos: Rect<usize>,
+    heights: &[f64],
+    black_white: (f64, f64),
+    displayer: &TextureRenderer,
+    screen_size: (f32, f32),
+) -> Result<()> {
+    displayer.rect(pos.clone(), [0.1, 0.1, 0.1, 1.0], screen_size)?;
+    let bar_width = (pos.width / heights.len().max(1)).max(1);
+    for (index, &height) in heights.iter().enumerate() {
+        let bar_height = (height * pos.height as f64) as usize;
+        if bar_height == 0 {
+            continue;
+        }
+        displayer.rect(
+            Rect::new(
+                pos.x + index * pos.width / heights.len(),
+                pos.bottom() - bar_height,
+                bar_width,
+                bar_height,
+            ),
+            [0.7, 0.7, 0.7, 1.0],
+            screen_size,
+        )?;
+    }
+    let (black, white) = black_white;
+    for &(point, color) in &[(black, [1.0, 0.0, 0.0, 1.0]), (white, [0.0, 0.5, 1.0, 1.0])] {
+        if (0.0..=1.0).contains(&point) {
+            let x = pos.x + (point * pos.width.saturating_sub(1) as f64) as usize;
+            displayer.line_y(x, pos.y, pos.bottom(), color, screen_size)?;
+        }
+    }
+    Ok(())
+}
+
 fn remap_to_scale_offset(from_start: f64, from_end: f64, to_start: f64, to_end: f64) -> (f64, f64) {
     // lerp(to_start, to_end, invlerp(from_start, from_end, t))
     // invLerp(a, b, t) = (t - a) / (b - a)
@@ -0,0 +1,235 @@
+use crate::{
+    calibration::Master,
+    camera::interface::{FrameMetadata, ROIImage},
+    fits::ImageType,
+    Result,
+};
+use std::{
+    fs::{create_dir_all, read_to_string, write},
+    path::PathBuf,
+};
+
+const INDEX_NAME: &str = "library.txt";
+const INDEX_HEADER: &str =
+    "# camera\ttype\texposure\tgain\toffset\ttemperature\tbin\twidth\theight\tpath";
+// dark current roughly doubles every this many degrees
+pub const DARK_DOUBLING_TEMPERATURE: f64 = 6.0;
+// gains and offsets closer than this are the same setting
+const SETTING_TOLERANCE: f64 = 0.5;
+// without a bias to separate it out, a dark only applies to frames this close in temperature
+const TEMPERATURE_TOLERANCE: f64 = 1.0;
+
+/// Masters are stored next to the index, below the user's data directory.
+pub fn directory() -> Result<PathBuf> {
+    let mut path = dirs::data_dir().ok_or("Unable to find data directory")?;
+    path.push("scopie");
+    path.push("masters");
+    Ok(path)
+}
+
+/// A master calibration frame, and the settings it was taken with.
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub camera: String,
+    pub image_type: ImageType,
+    // seconds
+    pub exposure: Option<f64>,
+    pub gain: Option<f64>,
+    pub offset: Option<f64>,
+    // degrees C
+    pub temperature: Option<f64>,
+    pub bin: usize,
+    pub size: (usize, usize),
+    pub path: PathBuf,
+}
+
+fn format_optional(value: Option<f64>) -> String {
+    value.map_or_else(|| "-".to_string(), |value| value.to_string())
+}
+
+// unknown settings count as a match, there's nothing to compare
+fn same_setting(setting: Option<f64>, frame_setting: Option<f64>) -> bool {
+    match (setting, frame_setting) {
+        (Some(setting), Some(frame_setting)) => (setting - frame_setting).abs() < SETTING_TOLERANCE,
+        _ => true,
+    }
+}
+
+fn parse_optional(value: &str) -> Result<Option<f64>> {
+    if value == "-" {
+        Ok(None)
+    } else {
+        Ok(Some(value.parse()?))
+    }
+}
+
+impl Entry {
+    pub fn new(image_type: ImageType, master: &Master) -> Self {
+        let meta = &master.image.meta;
+        Self {
+            camera: meta.camera.clone(),
+            image_type,
+            exposure: meta.exposure.map(|exposure| exposure.as_secs_f64()),
+            gain: meta.gain,
+            offset: meta.offset,
+            temperature: meta.temperature,
+            bin: meta.bin,
+            size: master.image.image.size,
+            path: master.path.clone(),
+        }
+    }
+
+    fn usable_for(&self, image_type: ImageType, image: &ROIImage) -> bool {
+        let meta = &image.meta;
+        self.camera == meta.camera
+            && self.image_type == image_type
+            && self.bin == meta.bin
+            && self.size.0 >= image.location.right()
+            && self.size.1 >= image.location.bottom()
+            && same_setting(self.gain, meta.gain)
+            && same_setting(self.offset, meta.offset)
+    }
+
+    // a dark that can be subtracted as is, without scaling
+    fn exact_for(&self, meta: &FrameMetadata) -> bool {
+        let same_exposure = match (self.exposure, meta.exposure) {
+            (Some(exposure), Some(frame_exposure)) => {
+                (frame_exposure.as_secs_f64() - exposure).abs() < 1e-3
+            }
+            _ => false,
+        };
+        let same_temperature = match (self.temperature, meta.temperature) {
+            (Some(temperature), Some(frame_temperature)) => {
+                (frame_temperature - temperature).abs() < TEMPERATURE_TOLERANCE
+            }
+            (None, None) => true,
+            _ => false,
+        };
+        same_exposure && same_temperature
+    }
+
+    // how different the dark current is from the frame's, in doublings. Unknown settings count
+    // as a match, there's nothing better to pick on.
+    fn distance(&self, meta: &FrameMetadata) -> f64 {
+        let exposure = match (self.image_type, self.exposure, meta.exposure) {
+            (ImageType::Dark, Some(exposure), Some(frame_exposure)) if exposure > 0.0 => {
+                (frame_exposure.as_secs_f64() / exposure).log2().abs()
+            }
+            _ => 0.0,
+        };
+        let temperature = match (self.temperature, meta.temperature) {
+            (Some(temperature), Some(frame_temperature)) => {
+                (frame_temperature - temperature).abs() / DARK_DOUBLING_TEMPERATURE
+            }
+            _ => 0.0,
+        };
+        exposure + temperature
+    }
+
+    fn parse(line: &str) -> Result<Self> {
+        let fields = line.split('\t').collect::<Vec<_>>();
+        match *fields.as_slice() {
+            [camera, image_type, exposure, gain, offset, temperature, bin, width, height, path] => {
+                Ok(Self {
+                    camera: camera.to_string(),
+                    image_type: image_type.parse()?,
+                    exposure: parse_optional(exposure)?,
+                    gain: parse_optional(gain)?,
+                    offset: parse_optional(offset)?,
+                    temperature: parse_optional(temperature)?,
+                    bin: bin.parse()?,
+                    size: (width.parse()?, height.parse()?),
+                    path: PathBuf::from(path),
+                })
+            }
+            _ => Err(format!("Invalid library entry: {}", line).into()),
+        }
+    }
+
+    fn format(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            self.camera,
+            self.image_type.short_name(),
+            format_optional(self.exposure),
+            format_optional(self.gain),
+            format_optional(self.offset),
+            format_optional(self.temperature),
+            self.bin,
+            self.size.0,
+            self.size.1,
+            self.path.display()
+        )
+    }
+}
+
+/// Index of master calibration frames, kept as one tab separated line per master.
+#[derive(Default)]
+pub struct Library {
+    entries: Vec<Entry>,
+}
+
+impl Library {
+    pub fn load() -> Result<Self> {
+        let mut path = directory()?;
+        path.push(INDEX_NAME);
+        let contents = if path.exists() {
+            read_to_string(path)?
+        } else {
+            String::new()
+        };
+        let entries = contents
+            .lines()
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(Entry::parse)
+            .collect::<Result<_>>()?;
+        Ok(Self { entries })
+    }
+
+    fn save(&self) -> Result<()> {
+        let mut path = directory()?;
+        create_dir_all(&path)?;
+        path.push(INDEX_NAME);
+        let mut contents = String::new();
+        contents.push_str(INDEX_HEADER);
+        contents.push('\n');
+        for entry in &self.entries {
+            contents.push_str(&entry.format());
+            contents.push('\n');
+        }
+        write(path, contents)?;
+        Ok(())
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// The master of this type closest to the frame's exposure and temperature, out of those
+    /// from the same camera with the same gain, offset and binning that cover the frame's ROI.
+    pub fn best_match(&self, image_type: ImageType, image: &ROIImage) -> Option<&Entry> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.usable_for(image_type, image))
+            .min_by(|l, r| {
+                let (l, r) = (l.distance(&image.meta), r.distance(&image.meta));
+                l.partial_cmp(&r).unwrap_or(std::cmp::Ordering::Equal)
+            })
+    }
+
+    /// A dark taken at the frame's exposure and temperature, for when there's no bias to scale
+    /// the dark current with.
+    pub fn exact_dark(&self, image: &ROIImage) -> Option<&Entry> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.usable_for(ImageType::Dark, image))
+            .find(|entry| entry.exact_for(&image.meta))
+    }
+
+    /// Adds the entry, replacing any previous one for the same file, and saves the index.
+    pub fn add(&mut self, entry: Entry) -> Result<()> {
+        self.entries.retain(|existing| existing.path != entry.path);
+        self.entries.push(entry);
+        self.save()
+    }
+}
@@ -1,12 +1,18 @@
 mod alg;
+mod calibration;
 mod camera;
+mod config;
 mod dms;
+mod fits;
 mod image_display;
+mod library;
 mod mount;
+mod naming;
 mod platesolve;
 mod text_input;
+mod writer;
 
-use camera::display::CameraDisplay;
+use camera::{display::CameraDisplay, interface::CameraRole, CameraId};
 use dms::Angle;
 use glutin::{
     self,
@@ -39,24 +45,66 @@ type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 #[derive(Debug)]
 pub enum UserUpdate {
     MountUpdate(mount::thread::MountData),
-    CameraUpdate(camera::thread::CameraData),
-    CameraData(Arc<camera::interface::ROIImage>),
-    SolveFinished(Angle, Angle),
-    ProcessResult(alg::process::ProcessResult),
+    CameraUpdate(CameraId, camera::thread::CameraData),
+    CameraData(CameraId, Arc<camera::interface::ROIImage>),
+    Calibrated(CameraId, calibration::CalibrationResult),
+    SolveFinished(CameraId, Angle, Angle),
+    ProcessResult(CameraId, alg::process::ProcessResult),
+    StackResult(CameraId, alg::livestack::StackResult),
+    LuckyResult(CameraId, alg::lucky::LuckyResult),
+    WriterUpdate(CameraId, writer::WriterStatus),
+    MasterFinished(
+        CameraId,
+        fits::ImageType,
+        std::result::Result<calibration::Master, String>,
+    ),
+    DefectsFound(CameraId, alg::defects::DefectMap),
 }
 type SendUserUpdate = EventLoopProxy<UserUpdate>;
 
+impl UserUpdate {
+    fn camera_id(&self) -> Option<CameraId> {
+        match *self {
+            UserUpdate::MountUpdate(_) => None,
+            UserUpdate::CameraUpdate(id, _)
+            | UserUpdate::CameraData(id, _)
+            | UserUpdate::Calibrated(id, _)
+            | UserUpdate::SolveFinished(id, _, _)
+            | UserUpdate::ProcessResult(id, _)
+            | UserUpdate::StackResult(id, _)
+            | UserUpdate::LuckyResult(id, _)
+            | UserUpdate::WriterUpdate(id, _)
+            | UserUpdate::MasterFinished(id, _, _)
+            | UserUpdate::DefectsFound(id, _) => Some(id),
+        }
+    }
+}
+
 fn read_png(path: impl AsRef<Path>) -> Result<CpuTexture<u16>> {
     let mut decoder = png::Decoder::new(File::open(path)?);
-    decoder.set_transformations(png::Transformations::IDENTITY);
+    // palettes and 1/2/4 bit images are expanded to 8 bit
+    decoder.set_transformations(png::Transformations::EXPAND);
     let (info, mut reader) = decoder.read_info()?;
-    assert_eq!(info.bit_depth, png::BitDepth::Sixteen);
-    assert_eq!(info.color_type, png::ColorType::Grayscale);
-    let mut buf = vec![0; info.buffer_size()];
+    let mut buf = vec![0; reader.output_buffer_size()];
     reader.next_frame(&mut buf)?;
+    let (color_type, bit_depth) = reader.output_color_type();
+    // colour images are averaged down to grey, alpha is ignored
+    let (samples, channels) = match color_type {
+        png::ColorType::Grayscale => (1, 1),
+        png::ColorType::GrayscaleAlpha => (2, 1),
+        png::ColorType::RGB => (3, 3),
+        png::ColorType::RGBA => (4, 3),
+        png::ColorType::Indexed => return Err("Unable to expand indexed png".into()),
+    };
+    let sample = |index: usize| match bit_depth {
+        png::BitDepth::Sixteen => u32::from(buf[index * 2]) << 8 | u32::from(buf[index * 2 + 1]),
+        // scale 8 bit up to the full 16 bit range
+        _ => u32::from(buf[index]) * 257,
+    };
     let mut buf16 = vec![0; info.width as usize * info.height as usize];
-    for i in 0..buf16.len() {
-        buf16[i] = u16::from(buf[i * 2]) << 8 | u16::from(buf[i * 2 + 1]);
+    for (i, value) in buf16.iter_mut().enumerate() {
+        let sum: u32 = (0..channels).map(|c| sample(i * samples + c)).sum();
+        *value = (sum / channels as u32) as u16;
     }
     Ok(CpuTexture::new(
         buf16,
@@ -64,6 +112,19 @@ fn read_png(path: impl AsRef<Path>) -> Result<CpuTexture<u16>> {
     ))
 }
 
+// FITS by extension, anything else is assumed to be a PNG
+fn read_image(path: impl AsRef<Path>) -> Result<camera::interface::ROIImage> {
+    let path = path.as_ref();
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("fits") | Some("fit") | Some("fts") => fits::read_fits(path),
+        _ => Ok(read_png(path)?.into()),
+    }
+}
+
 fn write_png(path: impl AsRef<Path>, img: &CpuTexture<u16>) -> Result<()> {
     let mut encoder = png::Encoder::new(File::create(path)?, img.size.0 as u32, img.size.1 as u32);
     encoder.set_color(png::ColorType::Grayscale);
@@ -80,7 +141,11 @@ fn write_png(path: impl AsRef<Path>, img: &CpuTexture<u16>) -> Result<()> {
 }
 
 struct Display {
-    camera_display: camera::display::CameraDisplay,
+    send_user_update: SendUserUpdate,
+    camera_displays: Vec<camera::display::CameraDisplay>,
+    // index into camera_displays, the camera that receives commands and is drawn largest
+    active_camera: usize,
+    next_camera_id: CameraId,
     mount_display: Option<mount::display::MountDisplay>,
     next_frequent_update: Instant,
     next_infrequent_update: Instant,
@@ -101,8 +166,60 @@ impl Display {
         (self.window_size.0 as f32, self.window_size.1 as f32)
     }
 
+    fn add_camera(&mut self, role: CameraRole) {
+        let id = self.next_camera_id;
+        self.next_camera_id += 1;
+        self.camera_displays
+            .push(CameraDisplay::new(self.send_user_update.clone(), id, role));
+        self.active_camera = self.camera_displays.len() - 1;
+    }
+
+    // target is the index of the camera the command is addressed to
+    fn camera_cmd(&mut self, cmd: &[&str], target: usize) -> Result<bool> {
+        match *cmd {
+            ["camera", "add", role] => match role.parse() {
+                Ok(role) => self.add_camera(role),
+                Err(_) => return Ok(false),
+            },
+            ["camera", "focus", id] => {
+                let id = id.parse::<CameraId>()?;
+                match self.camera_displays.iter().position(|c| c.id() == id) {
+                    Some(index) => self.active_camera = index,
+                    None => return Err(format!("No camera with id {}", id).into()),
+                }
+            }
+            ["camera", "remove"] if self.camera_displays.len() > 1 => {
+                self.camera_displays.remove(target);
+                if target == self.active_camera {
+                    self.active_camera = 0;
+                } else if target < self.active_camera {
+                    self.active_camera -= 1;
+                }
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
     fn run_cmd_impl(&mut self, text: &str) -> Result<()> {
-        let cmd = text.split_whitespace().collect::<Vec<_>>();
+        let mut cmd = text.split_whitespace().collect::<Vec<_>>();
+        // "@id command" sends a single command to a camera other than the active one
+        let mut target_camera = self.active_camera;
+        if let Some(&first) = cmd.first() {
+            if first.starts_with('@') {
+                let id = first[1..].parse::<CameraId>()?;
+                target_camera = self
+                    .camera_displays
+                    .iter()
+                    .position(|c| c.id() == id)
+                    .ok_or_else(|| format!("No camera with id {}", id))?;
+                cmd.remove(0);
+            }
+        }
+        // these change the list of cameras, so target_camera may no longer be valid afterwards
+        if self.camera_cmd(&cmd, target_camera)? {
+            return Ok(());
+        }
         let mut command_okay = cmd.is_empty();
         match &cmd as &[&str] {
             ["wasd"] if self.mount_display.is_some() => {
@@ -115,7 +232,7 @@ impl Display {
             }
             _ => (),
         }
-        command_okay |= self.camera_display.cmd(&cmd)?;
+        command_okay |= self.camera_displays[target_camera].cmd(&cmd)?;
         if let Some(ref mut mount_display) = self.mount_display {
             match mount_display.cmd(&cmd) {
                 Ok(ok) => command_okay |= ok,
@@ -150,12 +267,16 @@ impl Display {
         let texture_renderer = TextureRenderer::new()?;
         let height = 20.0 * scale_factor as f32;
         let text_renderer = TextRenderer::new(height)?;
-        let send_user_update_2 = send_user_update.clone();
-        let camera_display = CameraDisplay::new(send_user_update_2);
-        let mount_display = Some(MountDisplay::new(MountAsync::new(send_user_update)));
+        let camera_display = CameraDisplay::new(send_user_update.clone(), 0, CameraRole::Main);
+        let mount_display = Some(MountDisplay::new(MountAsync::new(
+            send_user_update.clone(),
+        )));
         let text_input = text_input::TextInput::new();
         Ok(Self {
-            camera_display,
+            send_user_update,
+            camera_displays: vec![camera_display],
+            active_camera: 0,
+            next_camera_id: 1,
             mount_display,
             next_frequent_update: Instant::now(),
             next_infrequent_update: Instant::now(),
@@ -195,9 +316,21 @@ impl Display {
             self.next_infrequent_update += Duration::from_secs(1);
         }
         self.status.clear();
-        redraw |= self.camera_display.update();
-        self.camera_display
-            .status(&mut self.status, infrequent_update)?;
+        for camera_display in &mut self.camera_displays {
+            redraw |= camera_display.update();
+        }
+        if self.camera_displays.len() > 1 {
+            write!(&mut self.status, "cameras:")?;
+            for (index, camera_display) in self.camera_displays.iter().enumerate() {
+                if index == self.active_camera {
+                    write!(&mut self.status, " [{}]", camera_display.summary())?;
+                } else {
+                    write!(&mut self.status, " {}", camera_display.summary())?;
+                }
+            }
+            writeln!(&mut self.status)?;
+        }
+        self.camera_displays[self.active_camera].status(&mut self.status, infrequent_update)?;
         if let Some(ref mut mount_display) = self.mount_display {
             mount_display.status(&mut self.status)?;
         }
@@ -211,6 +344,10 @@ impl Display {
                 writeln!(&mut self.status, "wasd: mount control mode")?;
             }
             writeln!(&mut self.status, "zoom: camera zoom mode")?;
+            writeln!(&mut self.status, "camera add [main|guide]")?;
+            if self.camera_displays.len() > 1 {
+                writeln!(&mut self.status, "camera focus [id]|camera remove|@[id] [cmd]")?;
+            }
         }
         if self.old_status != self.status {
             self.old_status = self.status.clone();
@@ -240,9 +377,35 @@ impl Display {
         let width = (self.window_size.0 as isize - text_size.right() as isize)
             .try_into()
             .unwrap_or(1);
-        let camera_rect = Rect::new(text_size.right(), 0, width, input_pos_y);
-        self.camera_display
-            .draw(camera_rect, &self.texture_renderer, window_size_f32)?;
+        // the active camera gets the top two thirds, the rest share the bottom third
+        let others = self.camera_displays.len() - 1;
+        let main_height = if others == 0 {
+            input_pos_y
+        } else {
+            input_pos_y * 2 / 3
+        };
+        let mut other_index = 0;
+        for (index, camera_display) in self.camera_displays.iter_mut().enumerate() {
+            let camera_rect = if index == self.active_camera {
+                Rect::new(text_size.right(), 0, width, main_height)
+            } else {
+                let other_width = width / others;
+                let rect = Rect::new(
+                    text_size.right() + other_width * other_index,
+                    main_height,
+                    other_width,
+                    input_pos_y - main_height,
+                );
+                other_index += 1;
+                rect
+            };
+            camera_display.draw(
+                camera_rect,
+                &self.texture_renderer,
+                &mut self.text_renderer,
+                window_size_f32,
+            )?;
+        }
         Ok(())
     }
 
@@ -263,7 +426,7 @@ impl Display {
                 self.wasd_mount_mode = false;
             }
         } else if self.wasd_camera_mode {
-            self.camera_display.key_up(key);
+            self.camera_displays[self.active_camera].key_up(key);
         }
         Ok(())
     }
@@ -285,7 +448,7 @@ impl Display {
             if key == Key::Escape {
                 self.wasd_camera_mode = false;
             } else {
-                self.camera_display.key_down(key);
+                self.camera_displays[self.active_camera].key_down(key);
             }
         } else {
             self.text_input.key_down(key);
@@ -361,14 +524,24 @@ impl Display {
 
     fn user_update(&mut self, user_update: UserUpdate) -> Result<()> {
         match user_update {
-            UserUpdate::MountUpdate(_) => {
+            UserUpdate::MountUpdate(data) => {
+                for camera_display in &mut self.camera_displays {
+                    camera_display.mount_update(data.clone());
+                }
                 if let Some(ref mut mount_display) = self.mount_display {
-                    mount_display.user_update(user_update);
+                    mount_display.user_update(UserUpdate::MountUpdate(data));
                 }
             }
             _ => {
-                self.camera_display
-                    .user_update(user_update, &mut self.mount_display)?;
+                let id = user_update.camera_id();
+                let camera_display = self
+                    .camera_displays
+                    .iter_mut()
+                    .find(|c| Some(c.id()) == id);
+                // updates from a removed camera are dropped
+                if let Some(camera_display) = camera_display {
+                    camera_display.user_update(user_update, &mut self.mount_display)?;
+                }
             }
         }
         Ok(())
@@ -0,0 +1,194 @@
+use crate::{camera::interface::FrameMetadata, fits::Observation, Result};
+use std::path::{Path, PathBuf};
+
+// reproduces the original Desktop/<date>/<folder>/telescope.<date>.<time> layout
+pub const DEFAULT_TEMPLATE: &str = "{date:%Y_%m_%d}/{folder}/telescope.{date}.{time}";
+// attempts at suffixing .1, .2, ... before giving up on a name
+const MAX_COLLISIONS: usize = 1000;
+// strftime specifiers the time crate formats without panicking
+const FORMAT_SPECIFIERS: &str = "aAbBCdDFgGHIjmMNpPrRSTuUVwWyYz%";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Token {
+    Target,
+    Type,
+    Filter,
+    Exposure,
+    Gain,
+    Temperature,
+    Sequence,
+    Date,
+    Time,
+    Folder,
+}
+
+impl Token {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "target" => Some(Token::Target),
+            "type" => Some(Token::Type),
+            "filter" => Some(Token::Filter),
+            "exposure" => Some(Token::Exposure),
+            "gain" => Some(Token::Gain),
+            "temp" => Some(Token::Temperature),
+            "seq" => Some(Token::Sequence),
+            "date" => Some(Token::Date),
+            "time" => Some(Token::Time),
+            "folder" => Some(Token::Folder),
+            _ => None,
+        }
+    }
+}
+
+enum Part {
+    Literal(String),
+    // only dates and times take an argument, a strftime style format
+    Token(Token, Option<String>),
+}
+
+// the time crate panics on unknown specifiers, so they have to be caught up front
+fn check_format(format: &str) -> Result<()> {
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            continue;
+        }
+        let mut specifier = chars.next();
+        // padding modifiers, e.g. %-d
+        if let Some('-') | Some('_') | Some('0') = specifier {
+            specifier = chars.next();
+        }
+        match specifier {
+            Some(specifier) if FORMAT_SPECIFIERS.contains(specifier) => (),
+            Some(specifier) => {
+                return Err(format!("Unsupported date format specifier %{}", specifier).into())
+            }
+            None => return Err(format!("Date format ends with %: {}", format).into()),
+        }
+    }
+    Ok(())
+}
+
+fn parse(template: &str) -> Result<Vec<Part>> {
+    let mut parts = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        if start > 0 {
+            parts.push(Part::Literal(rest[..start].to_string()));
+        }
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("Unclosed {{ in template: {}", template))?
+            + start;
+        let token = &rest[start + 1..end];
+        let (name, arg) = match token.find(':') {
+            Some(colon) => (&token[..colon], Some(token[colon + 1..].to_string())),
+            None => (token, None),
+        };
+        let parsed = Token::parse(name).ok_or_else(|| format!("Unknown token {{{}}}", name))?;
+        if arg.is_some() && parsed != Token::Date && parsed != Token::Time {
+            return Err(format!("Token {{{}}} doesn't take a format", name).into());
+        }
+        if let Some(ref arg) = arg {
+            check_format(arg)?;
+        }
+        parts.push(Part::Token(parsed, arg));
+        rest = &rest[end + 1..];
+    }
+    if !rest.is_empty() {
+        parts.push(Part::Literal(rest.to_string()));
+    }
+    Ok(parts)
+}
+
+pub fn validate(template: &str) -> Result<()> {
+    parse(template)?;
+    Ok(())
+}
+
+// keep user supplied values from adding directories or odd characters to the name
+fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' || c == '_' || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Everything a filename can be built from.
+pub struct NameFields<'a> {
+    pub meta: &'a FrameMetadata,
+    pub observation: &'a Observation,
+    // local time
+    pub time: time::OffsetDateTime,
+    pub folder: &'a str,
+}
+
+fn expand_token(token: Token, arg: &Option<String>, fields: &NameFields) -> String {
+    let meta = fields.meta;
+    let or_none = |value: Option<String>| value.unwrap_or_else(|| "none".to_string());
+    match token {
+        Token::Target if fields.observation.object.is_empty() => "none".to_string(),
+        Token::Target => sanitize(&fields.observation.object),
+        Token::Type => fields.observation.image_type.short_name().to_string(),
+        Token::Filter if fields.observation.filter.is_empty() => "none".to_string(),
+        Token::Filter => sanitize(&fields.observation.filter),
+        Token::Exposure => or_none(meta.exposure.map(|e| format!("{}s", e.as_secs_f64()))),
+        Token::Gain => or_none(meta.gain.map(|gain| gain.to_string())),
+        Token::Temperature => or_none(meta.temperature.map(|t| format!("{:.0}C", t))),
+        Token::Sequence => format!("{:04}", meta.sequence),
+        Token::Date => fields.time.format(arg.as_deref().unwrap_or("%Y-%m-%d")),
+        Token::Time => fields.time.format(arg.as_deref().unwrap_or("%H-%M-%S")),
+        // may deliberately contain subdirectories
+        Token::Folder => fields.folder.to_string(),
+    }
+}
+
+/// Builds a path below `root` from the template, without checking whether it exists.
+pub fn expand(root: &Path, template: &str, fields: &NameFields) -> Result<PathBuf> {
+    let mut name = String::new();
+    for part in parse(template)? {
+        match part {
+            Part::Literal(literal) => name.push_str(&literal),
+            Part::Token(token, arg) => name.push_str(&expand_token(token, &arg, fields)),
+        }
+    }
+    let mut path = root.to_path_buf();
+    // empty components come from empty tokens, e.g. an unset folder
+    for component in name.split('/') {
+        match component {
+            "" | "." => (),
+            ".." => return Err("Template must not contain ..".into()),
+            component => path.push(component),
+        }
+    }
+    if path == root {
+        return Err(format!("Template produced an empty filename: {}", template).into());
+    }
+    Ok(path)
+}
+
+/// Appends the extension, then .1, .2, ... before it if the file already exists.
+pub fn free_filename(path: PathBuf, extension: &str) -> Result<PathBuf> {
+    let with_extension = |suffix: String| {
+        let mut name = path.clone().into_os_string();
+        name.push(suffix);
+        PathBuf::from(name)
+    };
+    let first = with_extension(format!(".{}", extension));
+    if !first.exists() {
+        return Ok(first);
+    }
+    for i in 1..MAX_COLLISIONS {
+        let candidate = with_extension(format!(".{}.{}", i, extension));
+        if !candidate.exists() {
+            return Ok(candidate);
+        }
+    }
+    Err(format!("Unable to find a free filename for {}", first.display()).into())
+}
@@ -1,4 +1,4 @@
-use crate::{dms::Angle, Result, SendUserUpdate, UserUpdate};
+use crate::{camera::CameraId, dms::Angle, Result, SendUserUpdate, UserUpdate};
 use khygl::texture::CpuTexture;
 use regex::Regex;
 use std::{env::var, ffi::OsString, path::PathBuf, process::Command, sync::Once, thread};
@@ -49,7 +49,11 @@ const COMMAND: &[&str] = &[
     "1.1",
 ];
 
-pub fn platesolve(tex: &CpuTexture<u16>, send_user_update: SendUserUpdate) -> Result<()> {
+pub fn platesolve(
+    tex: &CpuTexture<u16>,
+    id: CameraId,
+    send_user_update: SendUserUpdate,
+) -> Result<()> {
     let linux_file_location = "/tmp/image.png";
     let (cmd, args) = if cfg!(windows) {
         let local_app_data = var("LOCALAPPDATA")?;
@@ -118,6 +122,7 @@ pub fn platesolve(tex: &CpuTexture<u16>, send_user_update: SendUserUpdate) -> Re
             .expect("couldn't parse solve-field dec");
         send_user_update
             .send_event(UserUpdate::SolveFinished(
+                id,
                 Angle::from_degrees(ra),
                 Angle::from_degrees(dec),
             ))
@@ -0,0 +1,173 @@
+use crate::{
+    camera::{interface::ROIImage, CameraId},
+    fits::{self, Observation},
+    naming::{self, NameFields},
+    Result, SendUserUpdate, UserUpdate,
+};
+use std::{
+    fmt::Write,
+    fs::create_dir_all,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc,
+    },
+    thread::{spawn, JoinHandle},
+};
+
+// full frames are large, so don't let too many pile up in memory
+const QUEUE_LENGTH: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SaveFormat {
+    Png,
+    Fits,
+}
+
+impl SaveFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            SaveFormat::Png => "png",
+            SaveFormat::Fits => "fits",
+        }
+    }
+}
+
+pub struct SaveJob {
+    pub image: Arc<ROIImage>,
+    pub root: PathBuf,
+    pub template: String,
+    pub folder: String,
+    // local time the save was requested, used for the filename
+    pub time: time::OffsetDateTime,
+    pub format: SaveFormat,
+    pub observation: Observation,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct WriterStatus {
+    pub written: u64,
+    pub last_file: Option<PathBuf>,
+    pub error: Option<String>,
+}
+
+fn write(job: &SaveJob) -> Result<PathBuf> {
+    let fields = NameFields {
+        meta: &job.image.meta,
+        observation: &job.observation,
+        time: job.time,
+        folder: &job.folder,
+    };
+    let path = naming::expand(&job.root, &job.template, &fields)?;
+    // names are only checked for collisions here, on the single writer thread, so two queued
+    // frames can't be given the same name
+    let filename = naming::free_filename(path, job.format.extension())?;
+    if let Some(directory) = filename.parent() {
+        if !directory.exists() {
+            create_dir_all(directory)?;
+        }
+    }
+    match job.format {
+        SaveFormat::Png => crate::write_png(&filename, &job.image.image)?,
+        SaveFormat::Fits => fits::write_fits(&filename, &job.image, &job.observation)?,
+    }
+    Ok(filename)
+}
+
+/// Saves images on a background thread, so that writing large frames doesn't stall the UI.
+pub struct Writer {
+    send: Option<mpsc::SyncSender<SaveJob>>,
+    thread: Option<JoinHandle<()>>,
+    queued: Arc<AtomicUsize>,
+    // frames that found the queue full and were left for the next one
+    skipped: u64,
+    status: WriterStatus,
+}
+
+impl Writer {
+    pub fn new(send_user_update: SendUserUpdate, id: CameraId) -> Self {
+        let (send, recv) = mpsc::sync_channel::<SaveJob>(QUEUE_LENGTH);
+        let queued = Arc::new(AtomicUsize::new(0));
+        let thread_queued = queued.clone();
+        let thread = spawn(move || {
+            let mut status = WriterStatus::default();
+            while let Ok(job) = recv.recv() {
+                match write(&job) {
+                    Ok(filename) => {
+                        status.written += 1;
+                        status.last_file = Some(filename);
+                    }
+                    Err(err) => status.error = Some(err.to_string()),
+                }
+                thread_queued.fetch_sub(1, Ordering::SeqCst);
+                // the UI may already be gone while flushing on exit, keep writing regardless
+                let _ = send_user_update.send_event(UserUpdate::WriterUpdate(id, status.clone()));
+            }
+        });
+        Self {
+            send: Some(send),
+            thread: Some(thread),
+            queued,
+            skipped: 0,
+            status: WriterStatus::default(),
+        }
+    }
+
+    /// Queues the job, returning false if the queue is full and the frame wasn't saved.
+    pub fn save(&mut self, job: SaveJob) -> Result<bool> {
+        let send = self.send.as_ref().expect("Writer used after drop");
+        self.queued.fetch_add(1, Ordering::SeqCst);
+        let result = send.try_send(job);
+        if result.is_err() {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+        }
+        match result {
+            Ok(()) => Ok(true),
+            Err(mpsc::TrySendError::Full(_)) => {
+                self.skipped += 1;
+                Ok(false)
+            }
+            Err(mpsc::TrySendError::Disconnected(_)) => Err("Writer thread disconnected".into()),
+        }
+    }
+
+    pub fn user_update(&mut self, status: WriterStatus) {
+        self.status = status;
+    }
+
+    pub fn status(&self, status: &mut String) -> Result<()> {
+        writeln!(
+            status,
+            "save queue: {}/{} written: {}",
+            self.queued.load(Ordering::SeqCst),
+            QUEUE_LENGTH,
+            self.status.written,
+        )?;
+        if self.skipped > 0 {
+            writeln!(
+                status,
+                "save error: queue full, {} frames not saved (disk too slow?)",
+                self.skipped
+            )?;
+        }
+        if let Some(ref last_file) = self.status.last_file {
+            writeln!(status, "last saved: {}", last_file.display())?;
+        }
+        if let Some(ref error) = self.status.error {
+            writeln!(status, "save error: {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Writer {
+    // wait for queued images to be written, rather than losing them on exit
+    fn drop(&mut self) {
+        self.send = None;
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                println!("Writer thread panicked");
+            }
+        }
+    }
+}